@@ -1,121 +1,699 @@
-use crate::Symbols;
+use crate::diagnostic::closest_match;
+use crate::expr::{evaluate, ExprError};
+use crate::macros::{Expander, Line};
+use crate::{
+    AssembleError, Block, ImageFormat, Object, SourceLine, Span, SymbolFile, Symbols, Tape,
+    TapeError,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::fs::File;
-use std::path::Path;
 use std::fs::OpenOptions;
-use std::io::{prelude::*, BufReader};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// A line of source split into words, along with where it came from.
+#[derive(Debug, Clone)]
+struct Statement {
+    words: Vec<String>,
+    /// 1-based column of each word.
+    columns: Vec<usize>,
+    file: String,
+    line: usize,
+    /// Position among the lines left after macro expansion.
+    index: usize,
+    text: String,
+    /// Value of the location counter when the statement was reached.
+    address: u16,
+    /// Name of the macro at the call site, if the line came from a macro.
+    expansion: Option<Span>,
+    /// Path in the `INCLUDE` that brought in `file`, if any.
+    include: Option<Span>,
+}
+
+impl Statement {
+    /// Span covering the words from `first` to `last`, inclusive.
+    fn span(&self, first: usize, last: usize) -> Span {
+        let end = self.columns[last] + self.words[last].chars().count();
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.columns[first],
+            len: end - self.columns[first],
+            text: self.text.clone(),
+            expansion: self.expansion.clone().map(Box::new),
+            included_from: self.include.clone().map(Box::new),
+        }
+    }
+
+    /// Where the statement was written, or the outermost macro call that
+    /// produced it.
+    fn origin(&self) -> SourceLine {
+        let mut origin = SourceLine {
+            file: self.file.clone(),
+            line: self.line,
+        };
+        let mut expansion = self.expansion.as_ref();
+        while let Some(call) = expansion {
+            origin = SourceLine {
+                file: call.file.clone(),
+                line: call.line,
+            };
+            expansion = call.expansion.as_deref();
+        }
+        origin
+    }
+}
+
+/// An `IF`, `IFDEF` or `IFNDEF` whose `ENDIF` has not been reached yet.
+struct Conditional {
+    /// Whether statements in the current branch are assembled.
+    active: bool,
+    /// Whether a branch has been chosen already, so `ELSE` is skipped.
+    taken: bool,
+    in_else: bool,
+    /// The directive and where it is, for reporting a missing `ENDIF`.
+    directive: String,
+    span: Span,
+}
+
+/// How an operand changes when a relocatable module is placed in memory.
+enum Relocation {
+    /// By the address the module is placed at.
+    Internal,
+    /// By the address of an imported symbol.
+    External(String),
+}
+
+/// Files read and written by `Assembler::run`.
+pub struct AssemblerConfig {
+    pub input: String,
+    pub output: String,
+    /// Where to save the symbol table and source map, if anywhere.
+    pub symbols: Option<String>,
+    /// Where to save the human readable listing, if anywhere.
+    pub listing: Option<String>,
+    /// Constants defined before assembly starts, as names and expressions.
+    pub defines: Vec<(String, String)>,
+    /// Write a relocatable object for the linker instead of a tape.
+    pub object: bool,
+    /// Write a tape that the CPU can load at any address.
+    pub relocatable: bool,
+    /// Write a checksum after every record of the tape.
+    pub checksums: bool,
+    /// Format of the output, if not writing an object.
+    pub format: ImageFormat,
+}
+
+impl AssemblerConfig {
+    pub fn new(input: String, output: String) -> AssemblerConfig {
+        AssemblerConfig {
+            input,
+            output,
+            symbols: None,
+            listing: None,
+            defines: vec![],
+            object: false,
+            relocatable: false,
+            checksums: false,
+            format: ImageFormat::Tape,
+        }
+    }
+}
 
 pub struct Assembler {
     file: String,
     line_count: u16,
     symbols: Symbols,
-    listing: Vec<Vec<String>>,
+    listing: Vec<Statement>,
     distances: Vec<u16>,
+    source_map: Vec<(u16, SourceLine)>,
+    errors: Vec<AssembleError>,
+    /// Every line of the source with macros expanded, for the listing.
+    lines: Vec<Line>,
+    /// Location counter at each statement, by index into `lines`.
+    line_addresses: HashMap<usize, u16>,
+    /// Bytes emitted by each statement, by index into `lines`.
+    emitted: HashMap<usize, Vec<u8>>,
+    /// Origin and length of each block, by index of the `@` or `$` that
+    /// starts it.
+    blocks: HashMap<usize, (u16, u16)>,
+    /// Index of every `$` statement.
+    reservations: HashSet<usize>,
+    /// Where each label and constant is defined.
+    definitions: HashMap<String, SourceLine>,
+    /// Index of the `#` statement, whose jump goes to the tape header.
+    entry_line: Option<usize>,
+    /// Conditional blocks enclosing the current statement, innermost last.
+    conditions: Vec<Conditional>,
+    /// Whether addresses are relative, to be fixed up by the linker.
+    relocatable: bool,
+    /// Names declared with `<`, resolved by the linker.
+    imports: Vec<String>,
+    /// `>` statements, checked once every label is known.
+    exports: Vec<Statement>,
+    /// Instructions whose operand must be relocated, by address.
+    relocations: Vec<(u16, Relocation)>,
+    /// Location counter at the `#` statement.
+    end: u16,
 }
 
 impl Assembler {
-    pub fn run(input_filename: String, output_filename: String) {
+    pub fn run(config: AssemblerConfig) {
+        if config.format != ImageFormat::Tape && (config.relocatable || config.checksums) {
+            eprintln!("Relocation bits and checksums can only be written to a tape");
+            std::process::exit(1);
+        }
+        let output_filename = config.output;
         if fs::remove_file(&output_filename).is_ok() {
             info!("Overwrote previously existing program.bin");
         }
-        let mut ass = Assembler::new(input_filename);
-        ass.run_first_pass();
-        let buffer = ass.run_second_pass();
-        // let mut d: Vec<(String, u16)> = ass.symbols.table.drain().collect();
-        // d.sort_by(|x, y| x.1.cmp(&y.1));
-        // for (k, v) in d.iter() {
-        //     println!("{}: {}", k , v);
-        // }
-        let save_path = Path::new(&output_filename);
-        if !save_path.exists() {
-            fs::create_dir_all(save_path.parent().unwrap()).unwrap();
+        let mut ass = Assembler::new(config.input);
+        ass.set_relocatable(config.object || config.relocatable);
+        for (name, expression) in config.defines.iter() {
+            if let Err(error) = ass.define(name, expression) {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
         }
-        let mut f = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(save_path)
-            .unwrap_or_else(|err| panic!("{}", err));
-
-        match f.write(&buffer) {
-            Ok(_) => info!("Binary successfully generated"),
-            Err(err) => panic!("{}", err),
+        let buffer = ass.assemble().unwrap_or_else(|errors| {
+            for error in errors.iter() {
+                eprintln!("{}", error);
+            }
+            eprintln!("Could not assemble due to {} error(s)", errors.len());
+            std::process::exit(1);
+        });
+        if let Some(symbols_filename) = config.symbols {
+            match ass.symbol_file().save(&symbols_filename) {
+                Ok(_) => info!("Symbols saved to {}", symbols_filename),
+                Err(err) => {
+                    eprintln!("Unable to write {}: {}", symbols_filename, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(listing_filename) = config.listing {
+            match fs::write(&listing_filename, ass.listing_file()) {
+                Ok(_) => info!("Listing saved to {}", listing_filename),
+                Err(err) => {
+                    eprintln!("Unable to write {}: {}", listing_filename, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if config.object {
+            match ass.object(&buffer).save(&output_filename) {
+                Ok(_) => info!("Object successfully generated"),
+                Err(err) => {
+                    eprintln!("Unable to write {}: {}", output_filename, err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        let mut tape = match config.relocatable {
+            true => ass.relocatable_tape(&buffer).unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }),
+            false => {
+                Tape::parse(&buffer)
+                    .expect("assembler wrote a malformed tape")
+                    .0
+            }
         };
+        tape.checksums = config.checksums;
+        let buffer = config.format.write(&tape).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        let save_path = Path::new(&output_filename);
+        let written = save_path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(save_path)
+            })
+            .and_then(|mut f| f.write_all(&buffer));
+        match written {
+            Ok(_) => info!("Binary successfully generated"),
+            Err(err) => {
+                eprintln!("Unable to write {}: {}", output_filename, err);
+                std::process::exit(1);
+            }
+        }
     }
 
-    fn new(file: String) -> Assembler {
+    pub fn new(file: String) -> Assembler {
         let line_count = 0;
         let symbols = Symbols::new();
         let listing = vec![];
         let distances = vec![0];
+        let source_map = vec![];
+        let errors = vec![];
         Assembler {
             file,
             symbols,
             line_count,
             listing,
             distances,
+            source_map,
+            errors,
+            lines: vec![],
+            line_addresses: HashMap::new(),
+            emitted: HashMap::new(),
+            blocks: HashMap::new(),
+            reservations: HashSet::new(),
+            definitions: HashMap::new(),
+            entry_line: None,
+            conditions: vec![],
+            relocatable: false,
+            imports: vec![],
+            exports: vec![],
+            relocations: vec![],
+            end: 0,
+        }
+    }
+
+    /// Assembles at relative addresses for the linker, with `<` imports
+    /// allowed and operands that refer to labels recorded for relocation.
+    pub fn set_relocatable(&mut self, relocatable: bool) {
+        self.relocatable = relocatable;
+    }
+
+    /// Describes the tape from `assemble` as a relocatable object.
+    fn object(&self, tape: &[u8]) -> Object {
+        let (tape, _) = Tape::parse(tape).expect("assembler wrote a malformed tape");
+        let end = tape
+            .blocks
+            .iter()
+            .map(|block| block.origin + block.data.len() as u16)
+            .max()
+            .unwrap_or(0);
+        let mut object = Object::new();
+        object.size = end.max(self.end);
+        object.entry = Some(tape.entry_point());
+        object.blocks = tape.blocks;
+        object.imports = self.imports.clone();
+        for statement in self.exports.iter() {
+            if let Some(address) = self.symbols.label(&statement.words[1]) {
+                object.exports.insert(statement.words[1].clone(), address);
+            }
+        }
+        for (address, relocation) in self.relocations.iter() {
+            match relocation {
+                Relocation::Internal => object.relocations.push(*address),
+                Relocation::External(name) => object.externals.push((*address, name.clone())),
+            }
+        }
+        object
+    }
+
+    /// Describes the tape from `assemble` as one the CPU can load at any
+    /// address, which cannot import anything.
+    fn relocatable_tape(&self, tape: &[u8]) -> Result<Tape, AssembleError> {
+        if let Some(name) = self.imports.first() {
+            let message = format!("`{}` is imported, but a tape is never linked", name);
+            let help = "assemble with --object and combine modules with `link`".to_string();
+            return Err(AssembleError::new(message, None).with_help(help));
+        }
+        let (mut tape, _) = Tape::parse(tape).expect("assembler wrote a malformed tape");
+        let relocations = self.relocations.iter().map(|(address, _)| *address);
+        tape.relocations = Some(relocations.collect());
+        Ok(tape)
+    }
+
+    /// Binds `name` to the value of `expression` as if by `EQU`, before any
+    /// source is read.
+    pub fn define(&mut self, name: &str, expression: &str) -> Result<(), AssembleError> {
+        let lookup = |name: &str| self.symbols.value(name);
+        let value = evaluate(expression, 0, &lookup).map_err(|err| {
+            AssembleError::new(
+                format!("in definition of `{}`: {}", name, err.message),
+                None,
+            )
+        })?;
+        if !is_identifier(name) {
+            let message = format!("`{}` is not a valid name", name);
+            return Err(AssembleError::new(message, None));
+        }
+        if !self.symbols.define(name, value, false) {
+            let message = format!("`{}` is already defined", name);
+            return Err(AssembleError::new(message, None));
+        }
+        Ok(())
+    }
+
+    /// Runs both passes, returning the tape or every problem found along the way.
+    pub fn assemble(&mut self) -> Result<Vec<u8>, Vec<AssembleError>> {
+        self.run_first_pass();
+        let tape = self.run_second_pass();
+        for statement in self.exports.iter() {
+            let name = &statement.words[1];
+            if self.symbols.label(name).is_none() {
+                let message = format!("exported name `{}` is not a label", name);
+                self.errors
+                    .push(AssembleError::new(message, Some(statement.span(1, 1))));
+            }
+        }
+        match tape.to_bytes() {
+            Ok(buffer) if self.errors.is_empty() => return Ok(buffer),
+            Ok(_) => (),
+            // Whatever kept the program from emitting anything is reported
+            Err(TapeError::NoRecords) if !self.errors.is_empty() => (),
+            Err(TapeError::TooManyRecords(count)) => {
+                let message = format!(
+                    "the program needs {} tape records, but a tape holds at most 255",
+                    count
+                );
+                let help = "each block takes one record per 255 bytes; \
+                            join `@` sections or leave fewer gaps with `$`"
+                    .to_string();
+                self.errors
+                    .push(AssembleError::new(message, None).with_help(help));
+            }
+            Err(err) => self.errors.push(AssembleError::new(err.to_string(), None)),
+        }
+        // The passes report separately, so put the errors back in the order
+        // of the lines they point at, once includes are expanded
+        let mut positions: HashMap<(&str, usize), usize> = HashMap::new();
+        for (idx, line) in self.lines.iter().enumerate() {
+            positions
+                .entry((line.file.as_str(), line.number))
+                .or_insert(idx);
+        }
+        let mut errors = std::mem::take(&mut self.errors);
+        errors.sort_by_key(|error| {
+            error.span.as_ref().map(|span| {
+                let position = positions.get(&(span.file.as_str(), span.line));
+                (position.copied().unwrap_or(usize::MAX), span.line)
+            })
+        });
+        Err(errors)
+    }
+
+    /// Collects every label and the source line of every emitted statement.
+    fn symbol_file(&self) -> SymbolFile {
+        let mut symbol_file = SymbolFile::new();
+        for label in self.symbols.labels.iter() {
+            if let Some(address) = self.symbols.get(label) {
+                symbol_file.labels.insert(label.clone(), address);
+            }
+        }
+        for (address, position) in self.source_map.iter() {
+            symbol_file.lines.insert(*address, position.clone());
+        }
+        symbol_file
+    }
+
+    /// Renders the source next to addresses and bytes, then the symbol table
+    /// and a cross-reference of where each label is used.
+    fn listing_file(&self) -> String {
+        let mut out = format!("sisprog listing of {}\n\n", self.file);
+        out.push_str("ADDR  BYTES     LINE  SOURCE\n");
+        let mut file = &self.file;
+        for (line, source) in self.lines.iter().enumerate() {
+            // Expansions are listed under their call, wherever the macro lives
+            if source.file != *file && source.expansion.is_none() {
+                file = &source.file;
+                out.push_str(&format!("----  file {}\n", file));
+            }
+            let block = self
+                .blocks
+                .get(&line)
+                .map(|(origin, len)| format!("----  block at {:03X}, {} byte(s)\n", origin, len));
+            // A block started by `$` begins after the space it reserves
+            let reserves = self.reservations.contains(&line);
+            if !reserves {
+                out.push_str(block.as_deref().unwrap_or_default());
+            }
+            let is_header =
+                (self.blocks.contains_key(&line) && !reserves) || self.entry_line == Some(line);
+            let address = match self.line_addresses.get(&line) {
+                Some(address) if !is_header => format!("{:03X}", address),
+                _ => String::new(),
+            };
+            // Long strings carry on in rows of three bytes below their line
+            let rows: Vec<String> = match self.emitted.get(&line) {
+                Some(bytes) => bytes
+                    .chunks(3)
+                    .map(|chunk| {
+                        let row: Vec<String> =
+                            chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                        row.join(" ")
+                    })
+                    .collect(),
+                None => vec![String::new()],
+            };
+            // Lines from a macro are marked instead of numbered
+            let number = match source.expansion {
+                Some(_) => "+".to_string(),
+                None => source.number.to_string(),
+            };
+            out.push_str(&format!(
+                "{:<4}  {:<8}  {:>4}  {}\n",
+                address, rows[0], number, source.text
+            ));
+            let start = self.line_addresses.get(&line).copied().unwrap_or(0);
+            for (idx, row) in rows.iter().enumerate().skip(1) {
+                let row_address = start as usize + 3 * idx;
+                out.push_str(&format!("{:03X}   {}\n", row_address, row));
+            }
+            if reserves {
+                out.push_str(block.as_deref().unwrap_or_default());
+            }
+        }
+
+        let references = self.label_references();
+        let mut labels: Vec<(&String, u16)> = self
+            .symbols
+            .labels
+            .iter()
+            .filter_map(|label| self.symbols.get(label).map(|address| (label, address)))
+            .collect();
+        labels.sort();
+        out.push_str("\nSYMBOLS\n");
+        for (label, address) in labels.iter() {
+            out.push_str(&format!("  {:<20} {:03X}\n", label, address));
+        }
+        let mut constants: Vec<(&String, &i64)> = self.symbols.constants.iter().collect();
+        if !constants.is_empty() {
+            constants.sort();
+            out.push_str("\nCONSTANTS\n");
+            for (name, value) in constants {
+                out.push_str(&format!("  {:<20} {}\n", name, value));
+            }
+        }
+        out.push_str("\nCROSS REFERENCE\n");
+        for (label, _) in labels.iter() {
+            let defined = self
+                .definitions
+                .get(label.as_str())
+                .map(|at| self.location(at))
+                .unwrap_or_default();
+            let used = match references.get(label.as_str()) {
+                Some(lines) => {
+                    let lines: Vec<String> = lines.iter().map(|at| self.location(at)).collect();
+                    format!("used at {}", lines.join(", "))
+                }
+                None => "never used".to_string(),
+            };
+            out.push_str(&format!(
+                "  {:<20} defined at {:<5} {}\n",
+                label, defined, used
+            ));
+        }
+        out
+    }
+
+    /// Line number within the main file, or file and line elsewhere.
+    fn location(&self, at: &SourceLine) -> String {
+        match at.file == self.file {
+            true => at.line.to_string(),
+            false => at.to_string(),
+        }
+    }
+
+    /// Lines where each label appears in an operand.
+    fn label_references(&self) -> BTreeMap<&str, Vec<SourceLine>> {
+        let mut references: BTreeMap<&str, Vec<SourceLine>> = BTreeMap::new();
+        for statement in self.listing.iter() {
+            for word in statement.words.iter().skip(1) {
+                for name in identifiers(word) {
+                    if let Some(label) = self.symbols.labels.get(name) {
+                        let lines = references.entry(label.as_str()).or_default();
+                        let origin = statement.origin();
+                        if !lines.contains(&origin) {
+                            lines.push(origin);
+                        }
+                    }
+                }
+            }
         }
+        for lines in references.values_mut() {
+            lines.sort_unstable();
+        }
+        references
     }
 
     fn run_first_pass(&mut self) {
-        let file = self.load_file();
+        info!("Opening input asm file");
+        let path = Path::new(&self.file).to_path_buf();
+        let source = self.load_source(&path, None, &mut vec![]);
+        if source.is_empty() && !self.errors.is_empty() {
+            return;
+        }
         info!("Starting first pass of the assembler");
-        let statements = self.get_valid_statements(file);
+        let mut expander = Expander::new(&self.symbols);
+        expander.expand(&source);
+        self.errors.append(&mut expander.errors);
+        self.lines = expander.lines;
+        let statements = self.get_valid_statements();
         for statement in statements {
             if self.handle_statement(statement) {
                 break;
             }
         }
+        for conditional in std::mem::take(&mut self.conditions) {
+            let message = format!("`{}` has no matching `ENDIF`", conditional.directive);
+            self.errors
+                .push(AssembleError::new(message, Some(conditional.span)));
+        }
         let d_len = self.distances.len() - 1;
         if d_len < 1 {
-            eprintln!(
-                "File does not have enough basic blocks: A basic block must
- start with @ /xyz, and end with # LABEL"
-            );
-            std::process::exit(1);
+            self.errors.push(AssembleError::new(
+                "File does not have enough basic blocks: A basic block must \
+                 start with @ /xyz, and end with # LABEL"
+                    .to_string(),
+                None,
+            ));
+            return;
         }
         self.distances = self.distances[1..d_len].to_vec();
     }
 
-    fn run_second_pass(&mut self) -> Vec<u8> {
+    fn run_second_pass(&mut self) -> Tape {
         info!("Starting second pass of the assembler");
-        // println!("{:?}", self.distances);
-        // println!("{:?}", self.symbols);
-        // let mut base = 0;
-        let mut byte_buffer: Vec<u8> = vec![];
-        // This is the number of punch cards we have to read.
-        byte_buffer.push(self.distances.len() as u8);
-        for statement in self.listing.iter() {
+        let mut tape = Tape {
+            entry: 0,
+            blocks: vec![],
+            relocations: None,
+            checksums: false,
+        };
+        let mut errors = vec![];
+        let mut emitted = HashMap::new();
+        let mut blocks = HashMap::new();
+        // Values bound with SET are bound again as each line is reached
+        self.symbols.forget_variables();
+        let listing = std::mem::take(&mut self.listing);
+        for statement in listing.iter() {
             trace!("{:?}", statement);
-            let mnemonic = &statement[0];
-            let argument = &statement[1];
-            let code = self.symbols.get(&mnemonic);
-            let arg = self.convert_argument(argument);
-            if mnemonic == "@" {
-                let word = format!("{:04X}", arg);
-                trace!("{}", word);
-                let (msb, lsb) = self.split_word(word);
-                byte_buffer.push(msb);
-                byte_buffer.push(lsb);
-                byte_buffer.push(self.distances.remove(0) as u8);
+            if is_constant_directive(statement) {
+                if statement.words[1] == "SET" {
+                    if let Err(err) = self.define_constant(statement) {
+                        errors.push(err);
+                    }
+                }
+                continue;
+            }
+            let mnemonic = &statement.words[0];
+            let code = match self.symbols.opcode(mnemonic) {
+                Some(code) => code,
+                None => {
+                    errors.push(self.unknown_mnemonic(statement));
+                    continue;
+                }
+            };
+            if is_string_directive(mnemonic) {
+                match self.string_bytes(statement) {
+                    Ok(bytes) => {
+                        emit(&mut tape, statement.address, &bytes);
+                        emitted.insert(statement.index, bytes);
+                    }
+                    Err(err) => errors.push(err),
+                }
                 continue;
             }
+            let arg = match self.convert_argument(statement) {
+                Ok(arg) => arg,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            if mnemonic == "@" || mnemonic == "$" {
+                let origin = match mnemonic.as_str() {
+                    "$" => statement.address + arg,
+                    _ => arg,
+                };
+                if self.distances.is_empty() {
+                    continue;
+                }
+                let distance = self.distances.remove(0);
+                if distance > 0 {
+                    trace!("{:04X}", origin);
+                    tape.blocks.push(Block {
+                        origin,
+                        data: vec![],
+                    });
+                    blocks.insert(statement.index, (origin, distance));
+                }
+                continue;
+            }
+            let relocation = match self.relocatable {
+                true => self.relocation(statement, 1),
+                false => Ok(None),
+            };
+            let relocation = match relocation {
+                Ok(relocation) => relocation,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            let is_entry = self.entry_line == Some(statement.index);
+            match relocation {
+                Some(_) if mnemonic == "K" => {
+                    let span = statement.span(1, 1);
+                    let message = "a byte cannot hold a relocatable address".to_string();
+                    errors.push(AssembleError::new(message, Some(span)));
+                    continue;
+                }
+                Some(Relocation::External(_)) if is_entry => {
+                    let span = statement.span(1, 1);
+                    let message = "the entry point must be in this module".to_string();
+                    errors.push(AssembleError::new(message, Some(span)));
+                    continue;
+                }
+                Some(relocation) if !is_entry => {
+                    self.relocations.push((statement.address, relocation));
+                }
+                _ => (),
+            }
             if mnemonic == "K" {
                 let word = format!("{:04X}", arg);
                 trace!("{}", word);
                 let (_, lsb) = self.split_word(word);
-                byte_buffer.push(lsb);
+                emit(&mut tape, statement.address, &[lsb]);
+                emitted.insert(statement.index, vec![lsb]);
                 continue;
             }
             trace!("{:X}{:03X}", code, arg);
             let (msb, lsb) = self.split_word(format!("{:X}{:03X}", code, arg));
-            byte_buffer.push(msb);
-            byte_buffer.push(lsb);
-            // println!("{:?}", byte_buffer);
+            match is_entry {
+                true => tape.entry = u16::from_be_bytes([msb, lsb]),
+                false => emit(&mut tape, statement.address, &[msb, lsb]),
+            }
+            emitted.insert(statement.index, vec![msb, lsb]);
         }
-        // for byte in byte_buffer.iter() {
-        //     println!("{:02X}", byte);
-        // }
-        byte_buffer
+        self.errors.append(&mut errors);
+        self.listing = listing;
+        self.emitted = emitted;
+        self.blocks = blocks;
+        tape
     }
 
     fn split_word(&self, word: String) -> (u8, u8) {
@@ -124,101 +702,1127 @@ impl Assembler {
         (msb, lsb)
     }
 
-    fn parse_nums(&self, argument: &str) -> u16 {
-        if argument.starts_with('/') {
-            let a = u16::from_str_radix(&argument[1..], 16).unwrap();
-            return a;
+    /// Evaluates the operand at word `idx` of `statement`.
+    fn evaluate(&self, statement: &Statement, idx: usize) -> Result<i64, AssembleError> {
+        let lookup = |name: &str| self.lookup(name);
+        evaluate(&statement.words[idx], statement.address, &lookup)
+            .map_err(|err| self.expression_error(statement, idx, err))
+    }
+
+    /// Value of a label or constant. Imports are 0 until the linker places them.
+    fn lookup(&self, name: &str) -> Option<i64> {
+        match self.imports.iter().any(|import| import == name) {
+            true => Some(0),
+            false => self.symbols.value(name),
+        }
+    }
+
+    /// Works out how the operand at word `idx` moves with the module by
+    /// evaluating it again with every label moved by `SHIFT`, then with each
+    /// import it names moved by `SHIFT`.
+    fn relocation(
+        &self,
+        statement: &Statement,
+        idx: usize,
+    ) -> Result<Option<Relocation>, AssembleError> {
+        const SHIFT: i64 = 0x1000;
+        let text = &statement.words[idx];
+        let value = self.evaluate(statement, idx)?;
+        let moved = |labels: i64, import: Option<&str>| {
+            let lookup = |name: &str| match self.symbols.label(name) {
+                Some(address) => Some(address as i64 + labels),
+                None if Some(name) == import => Some(SHIFT),
+                None => self.lookup(name),
+            };
+            let location = statement.address + labels as u16;
+            evaluate(text, location, &lookup)
+                .map(|moved| moved - value)
+                .map_err(|err| self.expression_error(statement, idx, err))
+        };
+        let mut imports: Vec<&str> = identifiers(text)
+            .into_iter()
+            .filter(|name| self.imports.iter().any(|import| import == name))
+            .collect();
+        imports.dedup();
+        let relocation = match (moved(SHIFT, None)?, imports.as_slice()) {
+            (0, []) => return Ok(None),
+            (SHIFT, []) => Some(Relocation::Internal),
+            (0, [import]) if moved(0, Some(import))? == SHIFT => {
+                Some(Relocation::External(import.to_string()))
+            }
+            _ => None,
+        };
+        relocation.map(Some).ok_or_else(|| {
+            let message = format!("`{}` cannot be relocated", text);
+            let help = "an operand may add a constant to one label or import, \
+                        but not combine addresses"
+                .to_string();
+            AssembleError::new(message, Some(statement.span(idx, idx))).with_help(help)
+        })
+    }
+
+    fn expression_error(&self, statement: &Statement, idx: usize, err: ExprError) -> AssembleError {
+        let mut span = statement.span(idx, idx);
+        span.column += err.offset;
+        span.len = err.len;
+        let trailing = err.message.ends_with("after expression");
+        let error = AssembleError::new(err.message, Some(span));
+        if let Some(name) = err.undefined {
+            let labels = self
+                .symbols
+                .labels
+                .iter()
+                .chain(self.symbols.constants.keys())
+                .map(|label| label.as_str());
+            if let Some(suggestion) = closest_match(&name, labels) {
+                return error.with_help(format!("did you mean `{}`?", suggestion));
+            }
+        } else if trailing {
+            return error.with_help("comments must start with `;`".to_string());
+        }
+        error
+    }
+
+    /// Evaluates the operand of `statement` and checks that it fits in the
+    /// field it is encoded into.
+    fn convert_argument(&self, statement: &Statement) -> Result<u16, AssembleError> {
+        let value = self.evaluate(statement, 1)?;
+        let (min, max, field) = match statement.words[0].as_str() {
+            "K" => (-128, 255, "a byte"),
+            "LV" => (-128, 255, "the accumulator"),
+            "@" => (0, 0xFFF, "an address"),
+            _ => (0, 0xFFF, "a 12-bit operand"),
+        };
+        if value < min || value > max {
+            let span = statement.span(1, 1);
+            let message = format!("{} does not fit in {}", value, field);
+            let help = format!("values must be between {} and {}", min, max);
+            return Err(AssembleError::new(message, Some(span)).with_help(help));
+        }
+        match statement.words[0].as_str() {
+            "K" | "LV" => Ok((value & 0xFF) as u16),
+            _ => Ok(value as u16),
+        }
+    }
+
+    /// Bytes emitted by a `STR`, `STRZ` or `PSTR` statement.
+    fn string_bytes(&self, statement: &Statement) -> Result<Vec<u8>, AssembleError> {
+        let span = || Some(statement.span(1, 1));
+        let mut bytes = decode_string(&statement.words[1])
+            .map_err(|message| AssembleError::new(message, span()))?;
+        match statement.words[0].as_str() {
+            "STRZ" => bytes.push(0),
+            "PSTR" => {
+                if bytes.len() > 255 {
+                    let message = format!(
+                        "string is {} bytes long, but PSTR can only hold 255",
+                        bytes.len()
+                    );
+                    return Err(AssembleError::new(message, span()));
+                }
+                bytes.insert(0, bytes.len() as u8);
+            }
+            _ => (),
         }
-        argument.parse::<u16>().unwrap()
+        Ok(bytes)
     }
 
-    fn convert_argument(&self, argument: &str) -> u16 {
-        if argument.contains('+') {
-            let words = argument
-                .split('+')
-                .map(|x| x.to_owned())
-                .collect::<Vec<String>>();
-            return self.symbols.get(&words[0]) + words[1].parse::<u16>().unwrap();
+    /// Number of bytes a statement takes up in memory.
+    fn statement_size(&self, statement: &Statement) -> u16 {
+        let mnemonic = statement.words[0].as_str();
+        if is_string_directive(mnemonic) {
+            // Bad strings are reported in the second pass
+            return match self.string_bytes(statement) {
+                Ok(bytes) => bytes.len() as u16,
+                Err(_) => 0,
+            };
         }
-        if self.symbols.table.get(argument).is_some() {
-            return self.symbols.get(&argument.to_string());
+        match mnemonic {
+            "K" => 1,
+            _ => 2,
         }
-        if argument.contains('"') {
-            return argument.chars().nth(1).unwrap() as u16;
+    }
+
+    fn unknown_mnemonic(&self, statement: &Statement) -> AssembleError {
+        let mnemonic = &statement.words[0];
+        let span = statement.span(0, 0);
+        let error = AssembleError::new(format!("unknown instruction `{}`", mnemonic), Some(span));
+        match closest_match(mnemonic, self.symbols.mnemonics()) {
+            Some(suggestion) => error.with_help(format!("did you mean `{}`?", suggestion)),
+            None => error,
         }
-        self.parse_nums(argument)
     }
 
-    fn handle_statement(&mut self, mut statement: Vec<String>) -> bool {
-        let n = statement.len();
-        let label = &statement[0];
-        // println!("{:?}", statement);
-        if n == 2 {
+    /// Binds the name of an `EQU` or `SET` statement to the value of its operand.
+    fn define_constant(&mut self, statement: &Statement) -> Result<(), AssembleError> {
+        let name = &statement.words[0];
+        let value = self.evaluate(statement, 2)?;
+        if self.relocatable && self.relocation(statement, 2)?.is_some() {
+            let span = statement.span(2, 2);
+            let message = format!("`{}` would hold a relocatable address", name);
+            let help = "write the expression where it is used instead".to_string();
+            return Err(AssembleError::new(message, Some(span)).with_help(help));
+        }
+        let redefinable = statement.words[1] == "SET";
+        if self.symbols.define(name, value, redefinable) {
+            self.definitions.insert(name.clone(), statement.origin());
+            return Ok(());
+        }
+        let span = statement.span(0, 0);
+        let message = if self.symbols.opcode(name).is_some() {
+            format!("`{}` is an instruction and cannot be used as a name", name)
+        } else {
+            format!("`{}` is already defined", name)
+        };
+        let error = AssembleError::new(message, Some(span));
+        match self.definitions.get(name) {
+            Some(at) if !redefinable && self.symbols.variables.contains(name) => Err(error
+                .with_help(format!(
+                    "`{}` was defined with SET at {}, use SET here too",
+                    name, at
+                ))),
+            Some(at) if !self.symbols.labels.contains(name) => Err(error.with_help(format!(
+                "previous definition at {}, use SET for values that change",
+                at
+            ))),
+            Some(at) => Err(error.with_help(format!("previous definition at {}", at))),
+            None => Err(error.with_help(format!("`{}` was defined on the command line", name))),
+        }
+    }
+
+    fn define_label(&mut self, statement: &Statement) {
+        let label = &statement.words[0];
+        if self.symbols.insert(label, self.line_count) {
+            self.definitions.insert(label.clone(), statement.origin());
+            return;
+        }
+        let span = statement.span(0, 0);
+        let message = if self.symbols.opcode(label).is_some() {
+            format!(
+                "`{}` is an instruction and cannot be used as a label",
+                label
+            )
+        } else if self.symbols.constants.contains_key(label) {
+            format!("`{}` is already defined as a constant", label)
+        } else {
+            format!("label `{}` is defined more than once", label)
+        };
+        self.errors.push(AssembleError::new(message, Some(span)));
+    }
+
+    /// Opens, switches or closes a conditional block if the statement is one
+    /// of `IF`, `IFDEF`, `IFNDEF`, `ELSE` or `ENDIF`. Returns whether it was.
+    fn handle_conditional(&mut self, statement: &Statement) -> bool {
+        let is_conditional =
+            |word: &str| matches!(word, "IF" | "IFDEF" | "IFNDEF" | "ELSE" | "ENDIF");
+        if statement.words.len() > 1 && is_conditional(&statement.words[1]) {
+            let span = statement.span(0, 0);
+            let message = format!("`{}` cannot have a label", statement.words[1]);
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return true;
+        }
+        let directive = statement.words[0].as_str();
+        if !is_conditional(directive) {
+            return false;
+        }
+        let span = statement.span(0, statement.words.len() - 1);
+        let takes_operand = directive.starts_with("IF");
+        if takes_operand != (statement.words.len() == 2) {
+            let message = match takes_operand {
+                true => format!("`{}` expects an operand", directive),
+                false => format!("`{}` takes no operand", directive),
+            };
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return true;
+        }
+        let enclosing = self.conditions.iter().all(|conditional| conditional.active);
+        match directive {
+            "ELSE" | "ENDIF" if self.conditions.is_empty() => {
+                let message = format!("`{}` without an `IF`", directive);
+                self.errors.push(AssembleError::new(message, Some(span)));
+            }
+            "ENDIF" => {
+                self.conditions.pop();
+            }
+            "ELSE" => {
+                let conditional = self.conditions.last_mut().unwrap();
+                if conditional.in_else {
+                    let message = "`ELSE` after `ELSE`".to_string();
+                    self.errors.push(AssembleError::new(message, Some(span)));
+                }
+                conditional.active = !conditional.taken;
+                conditional.taken = true;
+                conditional.in_else = true;
+            }
+            _ => {
+                // Conditions inside skipped blocks are not evaluated at all
+                let active = enclosing && self.condition(statement);
+                self.conditions.push(Conditional {
+                    active,
+                    taken: active || !enclosing,
+                    in_else: false,
+                    directive: directive.to_string(),
+                    span,
+                });
+            }
+        }
+        true
+    }
+
+    /// Whether the condition of an `IF`, `IFDEF` or `IFNDEF` holds. Only
+    /// constants and labels defined above it are known.
+    fn condition(&mut self, statement: &Statement) -> bool {
+        let operand = &statement.words[1];
+        match statement.words[0].as_str() {
+            "IFDEF" | "IFNDEF" if !is_identifier(operand) => {
+                let span = statement.span(1, 1);
+                let message = format!("`{}` is not a name", operand);
+                self.errors.push(AssembleError::new(message, Some(span)));
+                false
+            }
+            "IFDEF" => self.symbols.value(operand).is_some(),
+            "IFNDEF" => self.symbols.value(operand).is_none(),
+            _ => match self.evaluate(statement, 1) {
+                Ok(value) => value != 0,
+                Err(err) => {
+                    self.errors.push(err);
+                    false
+                }
+            },
+        }
+    }
+
+    /// Records a `<` import or `>` export.
+    fn handle_linkage(&mut self, statement: Statement) {
+        let directive = statement.words[0].as_str();
+        let span = statement.span(0, statement.words.len() - 1);
+        let name = match statement.words.get(1) {
+            Some(name) if statement.words.len() == 2 && is_identifier(name) => name,
+            _ => {
+                let message = format!("`{}` expects a name", directive);
+                self.errors.push(AssembleError::new(message, Some(span)));
+                return;
+            }
+        };
+        if directive == ">" {
+            self.exports.push(statement);
+            return;
+        }
+        if !self.relocatable {
+            let message = format!("`{}` can only be imported into an object", name);
+            let help = "assemble with --object and combine modules with `link`".to_string();
+            self.errors
+                .push(AssembleError::new(message, Some(span)).with_help(help));
+        } else if self.lookup(name).is_some() {
+            let message = format!("`{}` is already defined", name);
+            self.errors.push(AssembleError::new(message, Some(span)));
+        } else {
+            self.imports.push(name.clone());
+        }
+    }
+
+    fn handle_statement(&mut self, mut statement: Statement) -> bool {
+        statement.address = self.line_count;
+        if self.handle_conditional(&statement) {
+            return false;
+        }
+        if !self.conditions.iter().all(|conditional| conditional.active) {
+            return false;
+        }
+        if matches!(statement.words[0].as_str(), "<" | ">") {
+            self.handle_linkage(statement);
+            return false;
+        }
+        if !is_constant_directive(&statement) {
+            self.line_addresses.insert(statement.index, self.line_count);
+        }
+        let n = statement.words.len();
+        let label = &statement.words[0];
+        if n == 3 && is_constant_directive(&statement) {
+            if let Err(err) = self.define_constant(&statement) {
+                self.errors.push(err);
+            }
+            self.listing.push(statement);
+        } else if n == 2 && matches!(label.as_str(), "EQU" | "SET") {
+            let span = statement.span(0, 0);
+            let message = format!("`{}` needs a name to bind", label);
+            let help = format!("write `NAME {} value`", label);
+            self.errors
+                .push(AssembleError::new(message, Some(span)).with_help(help));
+        } else if n == 2 && matches!(statement.words[1].as_str(), "EQU" | "SET") {
+            let span = statement.span(1, 1);
+            let message = format!("`{}` expects an operand", statement.words[1]);
+            self.errors.push(AssembleError::new(message, Some(span)));
+        } else if n == 2 {
             if label.starts_with('@') {
-                let new_linecount = self.parse_nums(&statement[1]);
-                self.update_distances(new_linecount);
-                self.line_count = new_linecount;
-                self.listing.push(statement);
+                // Only labels defined above are known at this point
+                match self.convert_argument(&statement) {
+                    Ok(new_linecount) => {
+                        self.update_distances(new_linecount);
+                        self.line_count = new_linecount;
+                        self.listing.push(statement);
+                    }
+                    Err(err) => self.errors.push(err),
+                }
+            } else if label == "$" {
+                self.reserve(statement);
             } else if label.starts_with('#') {
+                self.end = self.line_count;
                 self.update_distances(self.line_count);
-                statement[0] = String::from("JP");
+                statement.words[0] = String::from("JP");
+                self.entry_line = Some(statement.index);
                 self.listing.insert(0, statement);
                 // TODO: Find a better solution than this hack
                 return true; // signals we have to break
             } else {
-                self.listing.push(statement.clone());
-                self.line_count += 2;
-                if label.starts_with('K') {
-                    self.line_count -= 1;
-                }
+                self.source_map.push((self.line_count, statement.origin()));
+                self.line_count += self.statement_size(&statement);
+                self.listing.push(statement);
             }
         } else if n == 1 {
-            self.symbols.insert(label, self.line_count);
-        } else if n == 3 {
-            self.symbols.insert(label, self.line_count);
-            statement.remove(0);
-            if statement[0].starts_with('K') {
-                self.line_count -= 1;
+            if self.symbols.opcode(label).is_some() {
+                let span = statement.span(0, 0);
+                let message = format!("`{}` expects an operand", label);
+                self.errors.push(AssembleError::new(message, Some(span)));
+            } else {
+                self.define_label(&statement);
             }
-            self.listing.push(statement);
-            self.line_count += 2;
+        } else if statement.words[1] == "$" {
+            self.define_label(&statement);
+            statement.words.remove(0);
+            statement.columns.remove(0);
+            self.reserve(statement);
         } else {
-            panic!("Line has more than 3 'words': {}", statement.join(" "))
+            self.define_label(&statement);
+            self.source_map.push((self.line_count, statement.origin()));
+            statement.words.remove(0);
+            statement.columns.remove(0);
+            self.line_count += self.statement_size(&statement);
+            self.listing.push(statement);
         }
         false
     }
 
+    /// Skips over the space reserved by `$`, starting a new block after it so
+    /// the gap is not written to the tape.
+    fn reserve(&mut self, statement: Statement) {
+        let size = match self.convert_argument(&statement) {
+            Ok(size) => size,
+            Err(err) => {
+                self.errors.push(err);
+                return;
+            }
+        };
+        let end = self.line_count as usize + size as usize;
+        if end > 0x1000 {
+            let span = statement.span(1, 1);
+            let message = format!(
+                "reserving {} byte(s) at {:03X} runs past the end of memory",
+                size, self.line_count
+            );
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return;
+        }
+        self.update_distances(end as u16);
+        self.line_count = end as u16;
+        self.reservations.insert(statement.index);
+        self.listing.push(statement);
+    }
+
     fn update_distances(&mut self, new_linecount: u16) {
         let n = self.distances.len() - 1;
-        self.distances[n] = self.line_count - self.distances.last().unwrap();
+        let start = *self.distances.last().unwrap();
+        self.distances[n] = self.line_count.wrapping_sub(start);
         self.distances.push(new_linecount);
-        // println!("{:?}", self.distances);
-        // println!("{:?}", self.line_count);
     }
 
-    fn load_file(&self) -> Vec<String> {
-        info!("Opening input asm file");
-        let file = match File::open(&self.file) {
-            Ok(x) => x,
-            Err(_) => std::process::exit(1),
+    /// Reads `path` and, in its place, every file it includes. `stack` holds
+    /// the canonical and given paths of the files being read, to catch files
+    /// that include themselves.
+    fn load_source(
+        &mut self,
+        path: &Path,
+        include: Option<Span>,
+        stack: &mut Vec<(PathBuf, String)>,
+    ) -> Vec<Line> {
+        let file = path.to_string_lossy().to_string();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                let message = format!("unable to read {}: {}", file, err);
+                self.errors.push(AssembleError::new(message, include));
+                return vec![];
+            }
         };
-        let reader = BufReader::new(file);
-        reader.lines().map(|x| x.unwrap()).collect()
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        stack.push((canonical, file.clone()));
+        let mut lines = vec![];
+        for (idx, text) in text.lines().enumerate() {
+            let line = Line::new(text.to_string(), file.clone(), idx + 1, include.clone());
+            let words = split_words(text);
+            if words.first().map(|word| word.text.as_str()) != Some("INCLUDE") {
+                lines.push(line);
+                continue;
+            }
+            let target = match words.as_slice() {
+                [_, name] => decode_string(&name.text).map(|bytes| {
+                    let name = String::from_utf8_lossy(&bytes).to_string();
+                    path.parent().unwrap_or(Path::new("")).join(name)
+                }),
+                _ => Err("`INCLUDE` expects a quoted path".to_string()),
+            };
+            let site = line.span(words.last().unwrap());
+            lines.push(Line {
+                code_len: 0,
+                ..line
+            });
+            let target = match target {
+                Ok(target) => target,
+                Err(message) => {
+                    let error = AssembleError::new(message, Some(site))
+                        .with_help("write `INCLUDE \"file.asm\"`".to_string());
+                    self.errors.push(error);
+                    continue;
+                }
+            };
+            let canonical = fs::canonicalize(&target).unwrap_or_else(|_| target.clone());
+            if stack.iter().any(|(file, _)| *file == canonical) {
+                let target = target.to_string_lossy().to_string();
+                let chain: Vec<&str> = stack
+                    .iter()
+                    .skip_while(|(file, _)| *file != canonical)
+                    .map(|(_, name)| name.as_str())
+                    .chain(std::iter::once(target.as_str()))
+                    .collect();
+                let message = format!("{} includes itself", target);
+                let error = AssembleError::new(message, Some(site))
+                    .with_help(format!("include chain is {}", chain.join(" -> ")));
+                self.errors.push(error);
+                continue;
+            }
+            lines.extend(self.load_source(&target, Some(site), stack));
+        }
+        stack.pop();
+        lines
     }
 
-    fn get_valid_statements(&self, lines: Vec<String>) -> Vec<Vec<String>> {
-        let mut result: Vec<Vec<String>> = vec![];
-        for line in lines {
-            let words: Vec<String> = line
-                .split(' ')
-                .take_while(|&word| !word.starts_with(';'))
-                .filter(|&x| x != "")
-                .map(|x| x.to_owned())
-                .collect();
-            if !words.is_empty() {
-                result.push(words);
+    /// Splits lines into an optional label, a mnemonic and an operand. Anything
+    /// after the mnemonic is the operand, so expressions may contain spaces.
+    fn get_valid_statements(&self) -> Vec<Statement> {
+        let mut result: Vec<Statement> = vec![];
+        for (idx, source) in self.lines.iter().enumerate() {
+            let line = &source.text[..source.code_len];
+            let mut words = split_words(line);
+            if words.is_empty() {
+                continue;
+            }
+            let operand = if self.symbols.opcode(&words[0].text).is_some() {
+                1
+            } else {
+                2
+            };
+            if words.len() > operand + 1 {
+                let rest = words.split_off(operand);
+                let text = line[rest[0].start..rest[rest.len() - 1].end].to_string();
+                words.push(Word { text, ..rest[0] });
             }
+            result.push(Statement {
+                columns: words.iter().map(|word| word.column).collect(),
+                words: words.into_iter().map(|word| word.text).collect(),
+                file: source.file.clone(),
+                line: source.number,
+                index: idx,
+                text: source.text.clone(),
+                address: 0,
+                expansion: source.expansion.clone(),
+                include: source.include.clone(),
+            });
         }
         result
     }
 }
+
+/// A run of non-whitespace characters in a line.
+pub(crate) struct Word {
+    pub text: String,
+    /// 1-based column of the first character.
+    pub column: usize,
+    /// Byte offsets of the word within the line.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Splits a line on whitespace up to a `;` comment, keeping quoted strings
+/// (which may hold spaces and semicolons) within a single word.
+pub(crate) fn split_words(line: &str) -> Vec<Word> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    let chars = line
+        .char_indices()
+        .chain(std::iter::once((line.len(), ' ')));
+    for (column, (byte, c)) in chars.enumerate() {
+        if in_string && byte < line.len() {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => escaped = false,
+            }
+            continue;
+        }
+        let ends_word = c.is_whitespace() || c == ';';
+        match (ends_word, start) {
+            (true, Some((first_byte, first_column))) => {
+                words.push(Word {
+                    text: line[first_byte..byte].to_owned(),
+                    column: first_column,
+                    start: first_byte,
+                    end: byte,
+                });
+                start = None;
+            }
+            (false, None) => {
+                start = Some((byte, column + 1));
+                in_string = c == '"';
+            }
+            (false, Some(_)) if c == '"' => in_string = true,
+            _ => (),
+        }
+        if c == ';' {
+            break;
+        }
+    }
+    words
+}
+
+/// Appends `bytes`, assembled at `address`, to the block being written.
+fn emit(tape: &mut Tape, address: u16, bytes: &[u8]) {
+    match tape.blocks.last_mut() {
+        Some(block) => block.data.extend(bytes),
+        None => tape.blocks.push(Block {
+            origin: address,
+            data: bytes.to_vec(),
+        }),
+    }
+}
+
+/// Whether a statement binds a name with `EQU` or `SET` rather than emitting
+/// anything.
+fn is_constant_directive(statement: &Statement) -> bool {
+    statement.words.len() == 3 && matches!(statement.words[1].as_str(), "EQU" | "SET")
+}
+
+pub(crate) fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_string_directive(mnemonic: &str) -> bool {
+    matches!(mnemonic, "STR" | "STRZ" | "PSTR")
+}
+
+/// Turns a quoted literal into bytes, handling `\n`, `\t`, `\r`, `\0`, `\"`,
+/// `\\` and `\xHH` escapes.
+pub(crate) fn decode_string(literal: &str) -> Result<Vec<u8>, String> {
+    let inner = match literal.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+        Some(inner) if literal.len() >= 2 => inner,
+        _ => return Err(format!("`{}` is missing a closing quote", literal)),
+    };
+    let mut bytes = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            '\\' => match chars.next() {
+                Some('n') => b'\n',
+                Some('t') => b'\t',
+                Some('r') => b'\r',
+                Some('0') => 0,
+                Some('"') => b'"',
+                Some('\\') => b'\\',
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(byte) if hex.len() == 2 => byte,
+                        _ => return Err(format!("`\\x{}` is not a valid hex escape", hex)),
+                    }
+                }
+                Some(other) => return Err(format!("unknown escape sequence `\\{}`", other)),
+                None => return Err(format!("`{}` ends with a lone backslash", literal)),
+            },
+            '"' => return Err(format!("`{}` has an unescaped quote inside it", literal)),
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("`{}` is not an ASCII character", c)),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+/// Names that may be labels within an operand, skipping string literals.
+fn identifiers(operand: &str) -> Vec<&str> {
+    let mut names = vec![];
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = None;
+    for (idx, c) in operand
+        .char_indices()
+        .chain(std::iter::once((operand.len(), ' ')))
+    {
+        if in_string {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => escaped = false,
+            }
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' || (c == '.' && start.is_some()) {
+            start = start.or(Some(idx));
+            continue;
+        }
+        if let Some(first) = start.take() {
+            let word = &operand[first..idx];
+            if word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+                names.push(word);
+            }
+        }
+        in_string = c == '"';
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` to a scratch directory named after the test, ready to
+    /// assemble the first of them.
+    fn assembler(test: &str, files: &[(&str, &str)]) -> Assembler {
+        let dir = std::env::temp_dir().join(format!("sisprog-{}-{}", test, std::process::id()));
+        for (name, text) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        Assembler::new(dir.join(files[0].0).to_string_lossy().to_string())
+    }
+
+    fn assemble(test: &str, files: &[(&str, &str)]) -> Result<Tape, Vec<AssembleError>> {
+        let bytes = assembler(test, files).assemble()?;
+        Ok(Tape::parse(&bytes).unwrap().0)
+    }
+
+    fn block(origin: u16, data: &[u8]) -> Block {
+        Block {
+            origin,
+            data: data.to_vec(),
+        }
+    }
+
+    fn messages(errors: &[AssembleError]) -> Vec<&str> {
+        errors.iter().map(|error| error.message.as_str()).collect()
+    }
+
+    /// File name and line of each error.
+    fn locations(errors: &[AssembleError]) -> Vec<(String, usize)> {
+        errors
+            .iter()
+            .filter_map(|error| error.span.as_ref())
+            .map(|span| {
+                let name = Path::new(&span.file).file_name().unwrap();
+                (name.to_string_lossy().to_string(), span.line)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn errors_follow_the_order_of_included_lines() {
+        let errors = assemble(
+            "include-order",
+            &[
+                (
+                    "main.asm",
+                    "@ /100\nSTART JP NOPE\n      INCLUDE \"lib.asm\"\n      # START\n",
+                ),
+                ("lib.asm", "      JP BAR\n      LV /1000\n      JP BAZ\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            locations(&errors),
+            vec![
+                ("main.asm".to_string(), 2),
+                ("lib.asm".to_string(), 1),
+                ("lib.asm".to_string(), 2),
+                ("lib.asm".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn strings_emit_one_byte_per_character() {
+        let source = concat!(
+            "@ /100\n",
+            "START K \" \"\n",
+            "      STR \"A\\n\\x41; \\\"\\\\\"\n",
+            "      STRZ \"Hi\"\n",
+            "      PSTR \"Hi\"\n",
+            "      # START\n",
+        );
+        let tape = assemble("strings", &[("main.asm", source)]).unwrap();
+        assert_eq!(tape.blocks, vec![block(0x100, b" A\nA; \"\\Hi\0\x02Hi")]);
+    }
+
+    #[test]
+    fn bad_strings_are_errors() {
+        let source =
+            "@ /100\nSTART STR \"A\\q\"\n      K \"AB\"\n      STR \"open\n      # START\n";
+        let errors = assemble("bad-strings", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec![
+                "unknown escape sequence `\\q`",
+                "`\"AB\"` is not a single character",
+                "`\"open` is missing a closing quote",
+            ]
+        );
+    }
+
+    #[test]
+    fn operands_are_expressions() {
+        let source = concat!(
+            "@ /100\n",
+            "START LV (2 + 3) * 4\n",
+            "      LD DATA + 1\n",
+            "      LV HI(DATA)\n",
+            "      LV LO(DATA)\n",
+            "      JP $\n",
+            "DATA  K /20 / 2\n",
+            "      K -1\n",
+            "      # START\n",
+        );
+        let tape = assemble("operands", &[("main.asm", source)]).unwrap();
+        assert_eq!(
+            tape.blocks,
+            vec![block(
+                0x100,
+                &[0x30, 0x14, 0x81, 0x0B, 0x30, 0x01, 0x30, 0x0A, 0x01, 0x08, 0x10, 0xFF]
+            )]
+        );
+    }
+
+    #[test]
+    fn operands_out_of_range_are_errors() {
+        let source = concat!(
+            "@ /100\n",
+            "START JP 4096\n",
+            "      LV -129\n",
+            "      K 256\n",
+            "      JP 1 / 0\n",
+            "      # START\n",
+        );
+        let errors = assemble("operand-range", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec![
+                "4096 does not fit in a 12-bit operand",
+                "-129 does not fit in the accumulator",
+                "256 does not fit in a byte",
+                "division by zero",
+            ]
+        );
+        assert_eq!(
+            errors[0].help.as_deref(),
+            Some("values must be between 0 and 4095")
+        );
+    }
+
+    #[test]
+    fn constants_take_no_space() {
+        let source = concat!(
+            "SIZE  EQU 3\n",
+            "N     SET 1\n",
+            "N     SET N + 1\n",
+            "@ /100\n",
+            "START LV SIZE * N\n",
+            "      LV FLAG\n",
+            "      # START\n",
+        );
+        let mut assembler = assembler("constants", &[("main.asm", source)]);
+        assembler.define("FLAG", "7").unwrap();
+        let bytes = assembler.assemble().unwrap();
+        let (tape, _) = Tape::parse(&bytes).unwrap();
+        assert_eq!(tape.blocks, vec![block(0x100, &[0x30, 0x06, 0x30, 0x07])]);
+    }
+
+    #[test]
+    fn constants_cannot_be_bound_twice() {
+        let source = concat!(
+            "SIZE  EQU 3\n",
+            "@ /100\n",
+            "START LV SIZE\n",
+            "SIZE  EQU 4\n",
+            "START EQU 1\n",
+            "      # START\n",
+        );
+        let errors = assemble("constants-twice", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec!["`SIZE` is already defined", "`START` is already defined"]
+        );
+        let help = errors[0].help.as_deref().unwrap();
+        assert!(
+            help.ends_with("main.asm:1, use SET for values that change"),
+            "{}",
+            help
+        );
+        let mut assembler = assembler("constants-defined", &[("main.asm", source)]);
+        assert!(assembler.define("FLAG", "1 +").is_err());
+        assert!(assembler.define("LV", "1").is_err());
+    }
+
+    #[test]
+    fn reserved_space_is_left_off_the_tape() {
+        let source = concat!(
+            "@ /100\n",
+            "START LD COUNT\n",
+            "      JP START\n",
+            "BUF   $ /10\n",
+            "COUNT K 1\n",
+            "      $ 2\n",
+            "END   K 2\n",
+            "      # START\n",
+        );
+        let tape = assemble("reserve", &[("main.asm", source)]).unwrap();
+        assert_eq!(
+            tape.blocks,
+            vec![
+                block(0x100, &[0x81, 0x14, 0x01, 0x00]),
+                block(0x114, &[0x01]),
+                block(0x117, &[0x02]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reserving_past_memory_is_an_error() {
+        let source = "@ /FF0\nSTART $ /20\n      # START\n";
+        let errors = assemble("reserve-end", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec!["reserving 32 byte(s) at FF0 runs past the end of memory"]
+        );
+    }
+
+    #[test]
+    fn macros_expand_with_their_own_labels() {
+        let source = concat!(
+            "MACRO PUTC CHAR\n",
+            "      LV CHAR\n",
+            "      PD /100\n",
+            "ENDM\n",
+            "MACRO WAIT N\n",
+            "LOOP  LV N\n",
+            "      JZ LOOP\n",
+            "ENDM\n",
+            "@ /100\n",
+            "START PUTC \"A\"\n",
+            "      WAIT 1\n",
+            "AGAIN WAIT /FF\n",
+            "      # START\n",
+        );
+        let tape = assemble("macros", &[("main.asm", source)]).unwrap();
+        assert_eq!(
+            tape.blocks,
+            vec![block(
+                0x100,
+                &[0x30, 0x41, 0xE1, 0x00, 0x30, 0x01, 0x11, 0x04, 0x30, 0xFF, 0x11, 0x08]
+            )]
+        );
+    }
+
+    #[test]
+    fn macro_errors_point_at_the_call() {
+        let source = concat!(
+            "MACRO SELF\n",
+            "      SELF\n",
+            "ENDM\n",
+            "MACRO TWO A, B\n",
+            "      K A + B\n",
+            "ENDM\n",
+            "@ /100\n",
+            "START SELF\n",
+            "      TWO 1\n",
+            "      # START\n",
+        );
+        let errors = assemble("macro-errors", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec![
+                "macro `SELF` is nested too deeply",
+                "macro `TWO` takes 2 argument(s) but 1 were given",
+            ]
+        );
+        let mut outermost = errors[0].span.as_ref().unwrap();
+        while let Some(expansion) = outermost.expansion.as_ref() {
+            outermost = expansion;
+        }
+        assert_eq!(outermost.line, 8);
+        assert_eq!(locations(&errors[1..]), vec![("main.asm".to_string(), 9)]);
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_their_file() {
+        let tape = assemble(
+            "include",
+            &[
+                (
+                    "main.asm",
+                    "@ /100\nSTART LD VALUE\n      INCLUDE \"lib/data.asm\"\n      # START\n",
+                ),
+                ("lib/data.asm", "VALUE K 5\n      INCLUDE \"more.asm\"\n"),
+                ("lib/more.asm", "MORE  K 6\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(tape.blocks, vec![block(0x100, &[0x81, 0x02, 0x05, 0x06])]);
+    }
+
+    #[test]
+    fn include_errors_point_at_the_include() {
+        let errors = assemble(
+            "include-errors",
+            &[
+                (
+                    "main.asm",
+                    "@ /100\nSTART K 1\n      INCLUDE \"a.asm\"\n      INCLUDE \"none.asm\"\n      # START\n",
+                ),
+                ("a.asm", "      INCLUDE \"b.asm\"\n"),
+                ("b.asm", "      INCLUDE \"a.asm\"\n"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", messages(&errors));
+        assert!(errors[0].message.ends_with("a.asm includes itself"));
+        assert!(errors[1].message.starts_with("unable to read "));
+        assert_eq!(
+            locations(&errors),
+            vec![("b.asm".to_string(), 1), ("main.asm".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn conditions_pick_statements_to_assemble() {
+        let source = concat!(
+            "DEBUG EQU 0\n",
+            "@ /100\n",
+            "START LV 1\n",
+            "      IF DEBUG\n",
+            "TRACE PD /100\n",
+            "      ELSE\n",
+            "      LV 2\n",
+            "      ENDIF\n",
+            "      IFDEF RELEASE\n",
+            "      LV 3\n",
+            "      ENDIF\n",
+            "      IFNDEF RELEASE\n",
+            "      IF DEBUG == 0 && 1\n",
+            "      LV 4\n",
+            "      ENDIF\n",
+            "      ENDIF\n",
+            "      # START\n",
+        );
+        let debug = assemble("conditions", &[("main.asm", source)]).unwrap();
+        assert_eq!(
+            debug.blocks,
+            vec![block(0x100, &[0x30, 0x01, 0x30, 0x02, 0x30, 0x04])]
+        );
+        let mut release = assembler("conditions-release", &[("main.asm", source)]);
+        release.define("RELEASE", "1").unwrap();
+        let (release, _) = Tape::parse(&release.assemble().unwrap()).unwrap();
+        assert_eq!(
+            release.blocks,
+            vec![block(0x100, &[0x30, 0x01, 0x30, 0x02, 0x30, 0x03])]
+        );
+    }
+
+    #[test]
+    fn skipped_statements_define_nothing() {
+        let source = concat!(
+            "@ /100\n",
+            "START LV 1\n",
+            "      IF 1\n",
+            "      JP TRACE\n",
+            "      IF 0\n",
+            "TRACE K 1\n",
+            "      ELSE\n",
+            "      ELSE\n",
+            "      ENDIF\n",
+            "      ENDIF\n",
+            "      ENDIF\n",
+            "      IF\n",
+            "      # START\n",
+        );
+        let errors = assemble("conditions-errors", &[("main.asm", source)]).unwrap_err();
+        assert_eq!(
+            messages(&errors),
+            vec![
+                "undefined symbol `TRACE`",
+                "`ELSE` after `ELSE`",
+                "`ENDIF` without an `IF`",
+                "`IF` expects an operand",
+            ]
+        );
+    }
+
+    #[test]
+    fn listing_shows_addresses_bytes_and_symbols() {
+        let source = concat!(
+            "MACRO PUTC CHAR\n",
+            "      LV CHAR\n",
+            "      PD /100\n",
+            "ENDM\n",
+            "@ /100\n",
+            "START PUTC \"A\"\n",
+            "      JP START\n",
+            "BUF   $ 2\n",
+            "MSG   STR \"Hello\"\n",
+            "      # START\n",
+        );
+        let mut assembler = assembler("listing", &[("main.asm", source)]);
+        assembler.assemble().unwrap();
+        let listing = assembler.listing_file();
+        let expected = concat!(
+            "\n",
+            "ADDR  BYTES     LINE  SOURCE\n",
+            "                   1  MACRO PUTC CHAR\n",
+            "                   2        LV CHAR\n",
+            "                   3        PD /100\n",
+            "                   4  ENDM\n",
+            "----  block at 100, 6 byte(s)\n",
+            "                   5  @ /100\n",
+            "100                6  START PUTC \"A\"\n",
+            "100   30 41        +        LV \"A\"\n",
+            "102   E1 00        +        PD /100\n",
+            "104   01 00        7        JP START\n",
+            "106                8  BUF   $ 2\n",
+            "----  block at 108, 5 byte(s)\n",
+            "108   48 65 6C     9  MSG   STR \"Hello\"\n",
+            "10B   6C 6F\n",
+            "      01 00       10        # START\n",
+            "\n",
+            "SYMBOLS\n",
+            "  BUF                  106\n",
+            "  MSG                  108\n",
+            "  START                100\n",
+            "\n",
+            "CROSS REFERENCE\n",
+            "  BUF                  defined at 8     never used\n",
+            "  MSG                  defined at 9     never used\n",
+            "  START                defined at 6     used at 7, 10\n",
+        );
+        assert!(listing.starts_with("sisprog listing of "));
+        assert_eq!(listing.split_once('\n').unwrap().1, expected);
+    }
+}
@@ -42,16 +42,5 @@ LDA0    LD  DATA
 
 @ /200
 DATA
-    K   "H"
-    K   "e"
-    K   "l"
-    K   "l"
-    K   "o"
-    K   ","
-    K   32                      ; Assembler breaks if you try to use a space character
-    K   "w"
-    K   "o"
-    K   "r"
-    K   "l"
-    K   "d"
+    STR "Hello, world"
     # START
@@ -1,108 +1,505 @@
-use crate::Mnemonics;
-use std::collections::HashMap;
+use crate::device::parse_mapping;
+use crate::number::parse_number;
+use crate::supervisor::{builtin_services, OsService};
+use crate::tape::EXTENDED_MAGIC;
+use crate::{
+    FaultSite, FileDevice, ImageFormat, IoDevice, Limit, MachineFault, Mnemonics, Profile,
+    ProfileOutput, Tape,
+};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::path::Path;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Added to a nonzero exit status given with `OS 0` to get the exit code of
+/// the process, which keeps it apart from the codes of faults.
+const PROGRAM_EXIT_BASE: i32 = 64;
+
+/// Number of instructions listed when a limit stops the machine.
+const HISTORY_LEN: usize = 8;
 
 pub struct Config {
     pub input: String,
     pub output: String,
-    pub trace: bool,
     pub memory: Vec<u8>,
+    /// Address of the first instruction executed.
+    pub start: u16,
+    /// Bytes at the start of the input that were already loaded, and so are
+    /// not left for `GD`.
+    pub skip: usize,
+    /// Devices selected by the operand of `GD` and `PD`. Device 0 reads the
+    /// rest of the input and writes the output, unless given here.
+    pub devices: Vec<(u16, Box<dyn IoDevice>)>,
+    /// Whether signed overflow in `+`, `-`, `*` or `/` faults instead of
+    /// wrapping around.
+    pub strict: bool,
+    /// Most instructions to execute before stopping.
+    pub max_instructions: Option<u64>,
+    /// Most bytes `PD` and `OS` may write before stopping.
+    pub max_output_bytes: Option<u64>,
+    /// Longest time to run for before stopping.
+    pub timeout: Option<Duration>,
+    /// Where `CPU::run` writes a profile of the run, if anywhere.
+    pub profile: Option<ProfileOutput>,
 }
 
 impl Config {
-    pub fn new(input: String, output: String, loader: String, trace: bool) -> Config {
+    /// Boots with `loader` in memory, so that it reads the tape at the start
+    /// of `input`. The tape must be a plain one, the loader cannot read the
+    /// extended header.
+    pub fn new(input: String, output: String, loader: String) -> Result<Config, Box<dyn Error>> {
         let mut memory = vec![0; 4096];
-        let mut loader_file = fs::File::open(loader).unwrap();
-        let mut loader_buffer = Vec::new();
-        loader_file.read_to_end(&mut loader_buffer).unwrap();
+        let read =
+            |path: &str| fs::read(path).map_err(|err| format!("Unable to read {}: {}", path, err));
+        let loader_buffer = read(&loader)?;
+        for (path, bytes) in [(&loader, &loader_buffer), (&input, &read(&input)?)] {
+            if bytes.starts_with(EXTENDED_MAGIC) {
+                let message = format!(
+                    "{} is an extended tape, which the loader cannot read. \
+                     Run it with `cpu --format tape` instead",
+                    path
+                );
+                return Err(message.into());
+            }
+        }
         // let loader = include_bytes!(loader);
         for (idx, byte) in loader_buffer.iter().skip(6).enumerate() {
             memory[idx] = *byte;
         }
         trace!("Memory initialized: {:?}", memory);
-        Config { input, output, trace, memory }
+        Ok(Config {
+            input,
+            output,
+            memory,
+            start: 0,
+            skip: 0,
+            devices: vec![],
+            strict: false,
+            max_instructions: None,
+            max_output_bytes: None,
+            timeout: None,
+            profile: None,
+        })
     }
+
+    /// Loads the program in `input`, written in `format`, instead of running
+    /// a loader. After a tape, the rest of the input is left for the program.
+    /// Text formats leave no input.
+    pub fn image(
+        input: String,
+        output: String,
+        format: ImageFormat,
+    ) -> Result<Config, Box<dyn Error>> {
+        let bytes = fs::read(&input)?;
+        let (tape, skip) = match format {
+            ImageFormat::Tape => Tape::parse(&bytes)?,
+            _ => (format.read(&bytes)?, bytes.len()),
+        };
+        let tape = tape.relocate(0)?;
+        info!(
+            "Loaded {} block(s) from a {:?} image",
+            tape.blocks.len(),
+            format
+        );
+        Ok(Config::loaded(input, output, &tape, skip))
+    }
+
+    /// Loads the tape at the start of `input` with every block moved up by
+    /// `base`, written as /1F0 or 496, instead of running a loader. Only a
+    /// relocatable tape can be moved by more than 0. The rest of the input
+    /// is left for the program.
+    pub fn relocated(input: String, output: String, base: &str) -> Result<Config, Box<dyn Error>> {
+        let base = parse_number(base)
+            .filter(|base| *base < 0x1000)
+            .ok_or_else(|| format!("Invalid load address {}", base))?;
+        let bytes = fs::read(&input)?;
+        let (tape, skip) = Tape::parse(&bytes)?;
+        let tape = tape.relocate(base)?;
+        info!("Loaded {} block(s) at {:03X}", tape.blocks.len(), base);
+        Ok(Config::loaded(input, output, &tape, skip))
+    }
+
+    /// Memory as the loader would leave it after reading `tape`, which must
+    /// fit in memory.
+    fn loaded(input: String, output: String, tape: &Tape, skip: usize) -> Config {
+        let mut memory = vec![0; 4096];
+        for block in tape.blocks.iter() {
+            let origin = block.origin as usize;
+            memory[origin..origin + block.data.len()].copy_from_slice(&block.data);
+        }
+        Config {
+            input,
+            output,
+            memory,
+            start: tape.entry_point(),
+            skip,
+            devices: vec![],
+            strict: false,
+            max_instructions: None,
+            max_output_bytes: None,
+            timeout: None,
+            profile: None,
+        }
+    }
+
+    /// Maps a device number to a device, given as `N=DEVICE` where DEVICE is
+    /// `stdin`, `stdout`, `null`, `buffer` or `file:PATH`.
+    pub fn attach(&mut self, mapping: &str) -> Result<(), String> {
+        let device = parse_mapping(mapping)?;
+        self.devices.push(device);
+        Ok(())
+    }
+}
+
+/// Status left by the last `+`, `-`, `*` or `/`. Other instructions leave
+/// the flags as they are.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Flags {
+    /// The operands taken as unsigned bytes gave a carry out of, or a borrow
+    /// into, the accumulator. For `*`, the unsigned product needs more than
+    /// 8 bits.
+    pub carry: bool,
+    /// The signed result did not fit in the accumulator and wrapped around.
+    pub overflow: bool,
+    /// The accumulator is zero.
+    pub zero: bool,
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "C={} V={} Z={}",
+            self.carry as u8, self.overflow as u8, self.zero as u8
+        )
+    }
+}
+
+/// What happened when the CPU executed a single instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    /// The instruction was executed and the machine can keep going.
+    Continued,
+    /// A `HM` instruction was executed. Holds its argument.
+    Halted(u16),
+    /// A `GD` instruction found no input. The PC is left pointing at the
+    /// instruction, so it is retried once more input is given to `feed_input`
+    /// or the device has more to read.
+    WaitingOnInput,
+    /// The program asked to stop with this exit status, between 0 and 127,
+    /// through `OS`.
+    Exited(u8),
+    /// The program asked to stop in the debugger through `OS`. The machine
+    /// can keep going.
+    Break,
+    /// The machine cannot go on.
+    Faulted(MachineFault),
 }
 
 pub struct CPU {
     memory: Vec<u8>,
     pc: u16,
     ac: i8,
-    trace: bool,
-    input_file: io::Bytes<fs::File>,
-    output_file: String,
+    flags: Flags,
+    /// Whether signed overflow faults instead of wrapping around.
+    strict: bool,
+    table: HashMap<u8, &'static str>,
+    site: FaultSite,
+    devices: HashMap<u16, Box<dyn IoDevice>>,
+    services: HashMap<u16, OsService>,
+    /// Number of instructions executed.
+    cycles: u64,
+    max_instructions: Option<u64>,
+    max_output_bytes: Option<u64>,
+    timeout: Option<Duration>,
+    /// When the first instruction was executed.
+    started: Option<Instant>,
+    /// Number of bytes written by `PD` and `OS`.
+    output_bytes: u64,
+    /// The instructions executed most recently, oldest first.
+    history: VecDeque<FaultSite>,
+    profile: Option<Profile>,
 }
 
 impl CPU {
-    pub fn run(config: Config) {
+    pub fn run(mut config: Config) {
         info!("Initializing CPU");
+        let profile_output = config.profile.take();
         let mut cpu = CPU::new(config).unwrap_or_else(|err| {
             eprintln!("{}", err);
             std::process::exit(1);
         });
-        let table = Mnemonics::new().from_code;
+        if profile_output.is_some() {
+            cpu.enable_profile();
+        }
         info!("Starting code execution");
-        loop {
-            trace!("PC is {}", cpu.pc);
-            trace!("AC is {}", cpu.ac);
-            let next_instruction = cpu.fetch();
-            cpu.decode_and_execute(next_instruction, &table);
-            debug!("");
-            if cpu.trace {
-                let mut input = String::new();
-                io::stdin()
-                    .read_line(&mut input)
-                    .expect("error: unable to read user input");
-                println!("{}", input);
+        let exit_code = loop {
+            match cpu.step() {
+                StepOutcome::Continued => (),
+                StepOutcome::Halted(_) => break 0,
+                StepOutcome::Exited(0) => break 0,
+                StepOutcome::Exited(status) => break PROGRAM_EXIT_BASE + status as i32,
+                StepOutcome::Break => info!("Break at {:03X}", cpu.last_site().pc),
+                StepOutcome::WaitingOnInput => {
+                    // Nothing else will ever be fed to the CLI machine
+                    let fault = MachineFault::ReadAfterEof(cpu.last_site());
+                    cpu.report_fault(&fault);
+                    break fault.exit_code();
+                }
+                StepOutcome::Faulted(fault) => {
+                    cpu.report_fault(&fault);
+                    break fault.exit_code();
+                }
             }
+            debug!("");
             // trace!("{:?}", cpu.memory);
+        };
+        if let (Some(output), Some(profile)) = (profile_output, cpu.profile()) {
+            if let Err(err) = output.write(profile) {
+                eprintln!("Unable to write profile to {}: {}", output.path, err);
+            }
+        }
+        std::process::exit(exit_code);
+    }
+
+    /// Fetches, decodes and executes the instruction at the PC.
+    pub fn step(&mut self) -> StepOutcome {
+        trace!("PC is {}", self.pc);
+        trace!("AC is {}", self.ac);
+        let result = self
+            .check_limits()
+            .and_then(|_| self.fetch())
+            .and_then(|instruction| self.decode_and_execute(instruction));
+        match result {
+            Ok(StepOutcome::WaitingOnInput) => StepOutcome::WaitingOnInput,
+            Ok(outcome) => {
+                self.cycles += 1;
+                if let Some(profile) = self.profile.as_mut() {
+                    profile.record(self.site);
+                }
+                outcome
+            }
+            Err(fault) => StepOutcome::Faulted(fault),
         }
     }
 
-    fn new(config: Config) -> Result<CPU, Box<dyn Error>> {
+    /// Stops the machine before the next instruction if it ran too many
+    /// instructions or for too long.
+    fn check_limits(&mut self) -> Result<(), MachineFault> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let limit = match (self.max_instructions, self.timeout) {
+            (Some(max), _) if self.cycles >= max => Limit::Instructions(max),
+            (_, Some(timeout)) if started.elapsed() >= timeout => Limit::Time(timeout),
+            _ => return Ok(()),
+        };
+        let byte = |address: u16| self.memory.get(address as usize).copied().unwrap_or(0);
+        let (msb, lsb) = (byte(self.pc), byte(self.pc.wrapping_add(1)));
+        self.site = FaultSite {
+            pc: self.pc,
+            opcode: msb >> 4,
+            operand: ((0x0F & msb as u16) << 8) + lsb as u16,
+        };
+        Err(MachineFault::LimitReached(self.site, limit))
+    }
+
+    /// Renders the instructions around `pc`, marking the one at `pc`.
+    pub fn context_window(&self, pc: u16, radius: u16) -> Vec<String> {
+        let start = pc.saturating_sub(2 * radius);
+        let end = pc.saturating_add(2 * radius);
+        (start..=end)
+            .step_by(2)
+            .filter_map(|address| {
+                let msb = *self.memory.get(address as usize)?;
+                let lsb = *self.memory.get(address as usize + 1)?;
+                let opcode = msb >> 4;
+                let arg = ((0x0F & msb as u16) << 8) + lsb as u16;
+                let marker = if address == pc { "=>" } else { "  " };
+                Some(format!(
+                    "{} {:03X}: {:02X}{:02X}  {:<2} {:03X}",
+                    marker, address, msb, lsb, self.table[&opcode], arg
+                ))
+            })
+            .collect()
+    }
+
+    /// Site of the instruction executed most recently.
+    pub fn last_site(&self) -> FaultSite {
+        self.site
+    }
+
+    pub(crate) fn report_fault(&self, fault: &MachineFault) {
+        eprintln!("{}", fault);
+        eprintln!("AC is {:02X} ({} in decimal)", self.ac, self.ac);
+        eprintln!("Flags are {}", self.flags);
+        for line in self.context_window(fault.site().pc, 3) {
+            eprintln!("{}", line);
+        }
+        if let MachineFault::LimitReached(..) = fault {
+            eprintln!("Last instructions executed:");
+            for site in self.history.iter() {
+                eprintln!(
+                    "   {:03X}: {:<2} {:03X}",
+                    site.pc, self.table[&site.opcode], site.operand
+                );
+            }
+        }
+    }
+
+    /// Queues bytes to be read by `GD 0`, if device 0 can take them.
+    pub fn feed_input(&mut self, bytes: &[u8]) -> bool {
+        match self.devices.get_mut(&0) {
+            Some(device) => device.feed(bytes),
+            None => false,
+        }
+    }
+
+    /// The device `GD` and `PD` select with `number`, if one is mapped.
+    pub fn device_mut(&mut self, number: u16) -> Option<&mut Box<dyn IoDevice>> {
+        self.devices.get_mut(&number)
+    }
+
+    /// Makes `OS number` run `service`, in place of any service it ran before.
+    pub fn register_service<F>(&mut self, number: u16, service: F)
+    where
+        F: FnMut(&mut CPU) -> Result<StepOutcome, MachineFault> + 'static,
+    {
+        self.services.insert(number, Box::new(service));
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Starts counting the instructions executed from here on.
+    pub fn enable_profile(&mut self) {
+        self.profile.get_or_insert_with(Profile::default);
+    }
+
+    /// Counts of the instructions executed since `enable_profile`.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn ac(&self) -> i8 {
+        self.ac
+    }
+
+    pub fn set_ac(&mut self, ac: i8) {
+        self.ac = ac;
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flags = flags;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn new(config: Config) -> Result<CPU, Box<dyn Error>> {
         let memory = config.memory;
-        let pc = 0;
+        let pc = config.start;
         let ac = 0;
-        let trace = config.trace;
-        let f = fs::File::open(config.input)?;
-        let input_file = f.bytes();
-        let output_file = config.output;
-        if fs::remove_file(&output_file).is_ok() {
-            info!("Overwrote previously existing output.bin");
-        }
-        let save_path = Path::new(&output_file);
-        if !save_path.exists() {
-            fs::create_dir_all(save_path.parent().unwrap()).unwrap();
+        let table = Mnemonics::new().from_code;
+        let site = FaultSite {
+            pc,
+            opcode: 0,
+            operand: 0,
+        };
+        let mut input = Vec::new();
+        fs::File::open(config.input)?.read_to_end(&mut input)?;
+        let input = input.split_off(config.skip.min(input.len()));
+        let mut devices: HashMap<u16, Box<dyn IoDevice>> = HashMap::new();
+        if !config.devices.iter().any(|(number, _)| *number == 0) {
+            // Leave no output from an earlier run behind
+            if fs::remove_file(&config.output).is_ok() {
+                info!("Overwrote previously existing {}", config.output);
+            }
+            devices.insert(0, Box::new(FileDevice::new(config.output, input)));
         }
+        devices.extend(config.devices);
 
         Ok(CPU {
             memory,
             pc,
             ac,
-            trace,
-            input_file,
-            output_file,
+            flags: Flags::default(),
+            strict: config.strict,
+            table,
+            site,
+            devices,
+            services: builtin_services(),
+            cycles: 0,
+            max_instructions: config.max_instructions,
+            max_output_bytes: config.max_output_bytes,
+            timeout: config.timeout,
+            started: None,
+            output_bytes: 0,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            profile: None,
         })
     }
 
-    fn fetch(&mut self) -> (u8, u8) {
-        let msb = self.memory[self.pc as usize];
-        let lsb = self.memory[(self.pc + 1) as usize];
+    fn fetch(&mut self) -> Result<(u8, u8), MachineFault> {
+        let pc = self.pc;
+        let msb = self.memory.get(pc as usize).copied();
+        let lsb = self.memory.get(pc as usize + 1).copied();
+        let (msb, lsb) = match (msb, lsb) {
+            (Some(msb), Some(lsb)) => (msb, lsb),
+            (msb, _) => {
+                let msb = msb.unwrap_or(0);
+                self.site = FaultSite {
+                    pc,
+                    opcode: msb >> 4,
+                    operand: (0x0F & msb as u16) << 8,
+                };
+                return Err(MachineFault::FetchOutOfRange(self.site));
+            }
+        };
         self.pc += 2;
         debug!("Fetched instruction {:02X}{:02X}", msb, lsb);
-        (msb, lsb)
+        Ok((msb, lsb))
+    }
+
+    fn read(&self, address: u16) -> Result<u8, MachineFault> {
+        match self.memory.get(address as usize) {
+            Some(byte) => Ok(*byte),
+            None => Err(MachineFault::AddressOutOfRange(self.site, address)),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), MachineFault> {
+        match self.memory.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(MachineFault::AddressOutOfRange(self.site, address)),
+        }
     }
 
-    fn decode_and_execute(&mut self, instruction: (u8, u8), table: &HashMap<u8, &str>) {
+    fn decode_and_execute(&mut self, instruction: (u8, u8)) -> Result<StepOutcome, MachineFault> {
         // TODO: Fix this terrible "table" solution: use Enums instead
         // TODO: Group functions into groups to minimize log repetition
-        if self.trace {
-            self.treat_user_input();
-        }
         let (msb, lsb) = instruction;
         let opcode = (msb & 0xF0) >> 4;
         let arg = ((0x0F & msb as u16) << 8) + lsb as u16;
@@ -125,126 +522,205 @@ impl CPU {
             15 => CPU::os_call,
             _ => panic!("Unknown function called"),
         };
-        let foo_name = table.get(&opcode).unwrap();
+        self.site = FaultSite {
+            pc: self.pc - 2,
+            opcode,
+            operand: arg,
+        };
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.site);
+        let foo_name = self.table.get(&opcode).unwrap();
         debug!("Executing {} {:03X}", foo_name, arg);
         f(self, arg)
     }
 
-    fn treat_user_input(&self) {}
-
-    fn jmp(&mut self, arg: u16) {
+    fn jmp(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
         self.pc = arg;
         debug!("PC set to {:03X} ({} in decimal)", arg, arg);
+        Ok(StepOutcome::Continued)
     }
 
-    fn jmp_if_zero(&mut self, arg: u16) {
+    fn jmp_if_zero(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
         if self.ac == 0 {
             self.pc = arg;
             debug!("PC set to {:03X} ({} in decimal)", arg, arg);
         } else {
             debug!("No jump");
         }
+        Ok(StepOutcome::Continued)
     }
 
-    fn jmp_if_neg(&mut self, arg: u16) {
+    fn jmp_if_neg(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
         if self.ac < 0 {
             self.pc = arg;
             debug!("PC set to {:03X} ({} in decimal)", arg, arg);
         } else {
             debug!("No jump");
         }
+        Ok(StepOutcome::Continued)
     }
 
-    fn load_value(&mut self, arg: u16) {
+    fn load_value(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
         self.ac = arg as i8;
         debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+        Ok(StepOutcome::Continued)
     }
 
-    fn add(&mut self, arg: u16) {
-        self.ac += self.memory[arg as usize] as i8;
-        debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+    fn add(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let value = self.read(arg)? as i8;
+        let (_, carry) = (self.ac as u8).overflowing_add(value as u8);
+        let (result, overflow) = self.ac.overflowing_add(value);
+        self.set_result(result, carry, overflow)
     }
 
-    fn sub(&mut self, arg: u16) {
-        self.ac -= self.memory[arg as usize] as i8;
-        debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+    fn sub(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let value = self.read(arg)? as i8;
+        let borrow = (self.ac as u8) < value as u8;
+        let (result, overflow) = self.ac.overflowing_sub(value);
+        self.set_result(result, borrow, overflow)
     }
 
-    fn mul(&mut self, arg: u16) {
-        self.ac *= self.memory[arg as usize] as i8;
-        debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+    fn mul(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let value = self.read(arg)? as i8;
+        let carry = (self.ac as u8 as u16) * (value as u8 as u16) > 0xFF;
+        let (result, overflow) = self.ac.overflowing_mul(value);
+        self.set_result(result, carry, overflow)
     }
 
-    fn div(&mut self, arg: u16) {
-        self.ac /= self.memory[arg as usize] as i8;
-        debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+    fn div(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let value = self.read(arg)? as i8;
+        if value == 0 {
+            return Err(MachineFault::DivisionByZero(self.site));
+        }
+        // -128 / -1 is the only quotient that does not fit
+        let (result, overflow) = self.ac.overflowing_div(value);
+        self.set_result(result, false, overflow)
     }
 
-    fn load_data(&mut self, arg: u16) {
-        self.ac = self.memory[arg as usize] as i8;
+    /// Leaves the result of an arithmetic instruction in AC and the flags,
+    /// unless it overflowed in strict mode.
+    fn set_result(
+        &mut self,
+        result: i8,
+        carry: bool,
+        overflow: bool,
+    ) -> Result<StepOutcome, MachineFault> {
+        if overflow && self.strict {
+            return Err(MachineFault::Overflow(self.site));
+        }
+        self.ac = result;
+        self.flags = Flags {
+            carry,
+            overflow,
+            zero: result == 0,
+        };
+        debug!(
+            "AC set to {:02X} ({} in decimal), flags {}",
+            self.ac, self.ac, self.flags
+        );
+        Ok(StepOutcome::Continued)
+    }
+
+    fn load_data(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        self.ac = self.read(arg)? as i8;
         debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+        Ok(StepOutcome::Continued)
     }
 
-    fn move_to_memory(&mut self, arg: u16) {
-        self.memory[arg as usize] = self.ac as u8;
+    fn move_to_memory(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        self.write(arg, self.ac as u8)?;
         debug!(
             "Mem pos {:03X} set to {:02X} ({} in decimal)",
             arg, self.ac, self.ac
         );
+        Ok(StepOutcome::Continued)
     }
 
-    fn subroutine_call(&mut self, arg: u16) {
-        let msb = (self.pc & 0x0F00 >> 8) as u8;
+    fn subroutine_call(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let msb = ((self.pc & 0x0F00) >> 8) as u8;
         let lsb = (self.pc & 0x00FF) as u8;
-        self.memory[arg as usize] = msb;
-        self.memory[(arg + 1) as usize] = lsb;
+        self.write(arg, msb)?;
+        self.write(arg + 1, lsb)?;
         self.pc = arg + 2;
-        debug!("PC set to {:03X} ({} in decimal)", self.pc, self.pc)
+        debug!("PC set to {:03X} ({} in decimal)", self.pc, self.pc);
+        Ok(StepOutcome::Continued)
     }
 
-    fn return_from_subroutine(&mut self, arg: u16) {
-        let msb = (0x0F & self.memory[arg as usize] as u16) << 8;
-        let lsb = self.memory[(arg + 1) as usize] as u16;
+    fn return_from_subroutine(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let msb = (0x0F & self.read(arg)? as u16) << 8;
+        let lsb = self.read(arg + 1)? as u16;
         self.pc = msb + lsb;
-        debug!("PC set to {:03X} ({} in decimal)", self.pc, self.pc)
+        debug!("PC set to {:03X} ({} in decimal)", self.pc, self.pc);
+        Ok(StepOutcome::Continued)
     }
 
-    fn halt_machine(&mut self, arg: u16) {
+    fn halt_machine(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
         info!("Halting machine.");
         trace!("{:?}", self.memory);
         self.pc = arg;
-        std::process::exit(0);
+        Ok(StepOutcome::Halted(arg))
     }
 
-    fn get_data(&mut self, _: u16) {
-        self.ac = match self.input_file.next() {
-            Some(res) => res.unwrap_or_else(|err| panic!(err)) as i8,
+    fn get_data(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let site = self.site;
+        let device = self.device(arg)?;
+        let byte = device
+            .read()
+            .map_err(|err| MachineFault::InputError(site, err.to_string()))?;
+        self.ac = match byte {
+            Some(byte) => byte as i8,
             None => {
-                eprintln!("Trying to read after EOF");
-                0
+                self.pc -= 2;
+                debug!("No input available, PC set back to {:03X}", self.pc);
+                return Ok(StepOutcome::WaitingOnInput);
             }
         };
         debug!("AC set to {:02X} ({} in decimal)", self.ac, self.ac);
+        Ok(StepOutcome::Continued)
     }
 
-    fn put_data(&mut self, _: u16) {
-        let mut f = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.output_file)
-            .unwrap_or_else(|err| panic!("{}", err));
-
-        match f.write(&[self.ac as u8]) {
-            Ok(_) => (),
-            Err(err) => panic!("{}", err),
-        };
+    fn put_data(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        self.put_byte(arg, self.ac as u8)?;
         debug!(
-            "Wrote {:02X} ({} in decimal) to output file",
-            self.ac, self.ac
+            "Wrote {:02X} ({} in decimal) to device {:X}",
+            self.ac, self.ac, arg
         );
+        Ok(StepOutcome::Continued)
+    }
+
+    /// Writes `byte` to device `number`, unless that goes over the output
+    /// limit.
+    pub(crate) fn put_byte(&mut self, number: u16, byte: u8) -> Result<(), MachineFault> {
+        let site = self.site;
+        if let Some(max) = self.max_output_bytes {
+            if self.output_bytes >= max {
+                return Err(MachineFault::LimitReached(site, Limit::OutputBytes(max)));
+            }
+        }
+        self.device(number)?
+            .write(byte)
+            .map_err(|err| MachineFault::OutputError(site, err.to_string()))?;
+        self.output_bytes += 1;
+        Ok(())
+    }
+
+    /// The device `GD` or `PD` selected with its operand.
+    fn device(&mut self, number: u16) -> Result<&mut Box<dyn IoDevice>, MachineFault> {
+        let site = self.site;
+        self.device_mut(number)
+            .ok_or(MachineFault::UnmappedDevice(site, number))
     }
 
-    fn os_call(&mut self, _: u16) {
-        unimplemented!()
+    fn os_call(&mut self, arg: u16) -> Result<StepOutcome, MachineFault> {
+        let mut service = self
+            .services
+            .remove(&arg)
+            .ok_or(MachineFault::UnimplementedOsCall(self.site))?;
+        let outcome = service(self);
+        // Unless the service registered another in its place
+        self.services.entry(arg).or_insert(service);
+        outcome
     }
 }
@@ -0,0 +1,351 @@
+use crate::number::parse_number;
+use crate::{Config, MachineFault, StepOutcome, SymbolFile, CPU};
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+const HELP: &str = "Commands:
+    b, break <loc>          set a breakpoint at an address or label
+    d, delete <loc>         remove a breakpoint
+    bl                      list breakpoints
+    s, step [n]             execute n instructions (default 1)
+    n, next                 step over SC calls
+    c, continue             run until a breakpoint, halt or fault
+    r, regs                 show PC, AC and flags
+    l, list [loc]           disassemble around loc (default PC)
+    x <loc> [len]           dump len bytes of memory (default 16)
+    set pc|ac <value>       change a register
+    set <loc> <value>       change a memory byte
+    in <byte>...            queue bytes for GD
+    h, help                 show this message
+    q, quit                 leave the debugger
+Addresses are written as /1F0 (hex) or 496 (decimal), labels as LABEL or LABEL+2";
+
+/// Drives a `CPU` one instruction at a time from commands typed by the user.
+pub struct Debugger {
+    cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+    symbols: SymbolFile,
+    stopped: bool,
+}
+
+impl Debugger {
+    pub fn run(config: Config, symbols_file: Option<String>) {
+        info!("Initializing CPU");
+        let cpu = CPU::new(config).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        let symbols = match symbols_file {
+            Some(file) => SymbolFile::load(&file).unwrap_or_else(|err| {
+                eprintln!("Unable to read symbols from {}: {}", file, err);
+                std::process::exit(1);
+            }),
+            None => SymbolFile::new(),
+        };
+        let mut debugger = Debugger::new(cpu, symbols);
+        println!("Type 'help' for a list of commands");
+        debugger.show_location();
+        loop {
+            print!("(sisprog) ");
+            io::stdout().flush().expect("error: unable to write prompt");
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("error: unable to read user input: {}", err);
+                    break;
+                }
+            }
+            if !debugger.execute(line.trim()) {
+                break;
+            }
+        }
+    }
+
+    pub fn new(cpu: CPU, symbols: SymbolFile) -> Debugger {
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            symbols,
+            stopped: false,
+        }
+    }
+
+    /// Runs one command. Returns false when the user asked to quit.
+    pub fn execute(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return true;
+        }
+        let result = match (words[0], &words[1..]) {
+            ("q", _) | ("quit", _) => return false,
+            ("h", _) | ("help", _) => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            ("b", [loc]) | ("break", [loc]) => self.resolve(loc).map(|address| {
+                self.breakpoints.insert(address);
+                println!("Breakpoint set at {}", self.describe(address));
+            }),
+            ("d", [loc]) | ("delete", [loc]) => self.resolve(loc).map(|address| {
+                if self.breakpoints.remove(&address) {
+                    println!("Breakpoint removed from {}", self.describe(address));
+                } else {
+                    println!("No breakpoint at {}", self.describe(address));
+                }
+            }),
+            ("bl", []) => {
+                for address in self.breakpoints.iter() {
+                    println!("{}", self.describe(*address));
+                }
+                Ok(())
+            }
+            ("s", []) | ("step", []) => {
+                self.step(1);
+                Ok(())
+            }
+            ("s", [n]) | ("step", [n]) => match n.parse::<usize>() {
+                Ok(n) => {
+                    self.step(n);
+                    Ok(())
+                }
+                Err(_) => Err(format!("Invalid step count: {}", n)),
+            },
+            ("n", []) | ("next", []) => {
+                self.next();
+                Ok(())
+            }
+            ("c", []) | ("continue", []) => {
+                self.resume(None);
+                Ok(())
+            }
+            ("r", []) | ("regs", []) => {
+                self.show_registers();
+                Ok(())
+            }
+            ("l", []) | ("list", []) => {
+                self.list(self.cpu.pc());
+                Ok(())
+            }
+            ("l", [loc]) | ("list", [loc]) => self.resolve(loc).map(|address| self.list(address)),
+            ("x", [loc]) => self.resolve(loc).map(|address| self.dump(address, 16)),
+            ("x", [loc, len]) => match (self.resolve(loc), parse_number(len)) {
+                (Ok(address), Some(len)) => {
+                    self.dump(address, len);
+                    Ok(())
+                }
+                (Err(err), _) => Err(err),
+                (_, None) => Err(format!("Invalid length: {}", len)),
+            },
+            ("set", [target, value]) => self.set(target, value),
+            ("in", bytes) if !bytes.is_empty() => {
+                let parsed: Option<Vec<u8>> = bytes
+                    .iter()
+                    .map(|byte| parse_number(byte).filter(|b| *b <= 0xFF).map(|b| b as u8))
+                    .collect();
+                match parsed {
+                    Some(parsed) if self.cpu.feed_input(&parsed) => Ok(()),
+                    Some(_) => Err("Device 0 can't take input".to_string()),
+                    None => Err("Input bytes must be between 0 and 255".to_string()),
+                }
+            }
+            _ => Err(format!("Unknown command: {}. Type 'help' for help", line)),
+        };
+        if let Err(err) = result {
+            println!("{}", err);
+        }
+        true
+    }
+
+    fn step(&mut self, n: usize) {
+        for _ in 0..n {
+            if !self.execute_one() {
+                break;
+            }
+        }
+        self.show_location();
+    }
+
+    /// Steps over `SC` by running until the instruction after it is reached.
+    fn next(&mut self) {
+        let pc = self.cpu.pc();
+        let opcode = self.cpu.memory().get(pc as usize).map(|msb| msb >> 4);
+        if opcode == Some(10) {
+            self.resume(Some(pc + 2));
+        } else {
+            self.step(1);
+        }
+    }
+
+    /// Runs until a breakpoint or `until` is reached, or the machine stops.
+    fn resume(&mut self, until: Option<u16>) {
+        loop {
+            if !self.execute_one() {
+                break;
+            }
+            let pc = self.cpu.pc();
+            if Some(pc) == until {
+                break;
+            }
+            if self.breakpoints.contains(&pc) {
+                println!("Breakpoint hit at {}", self.describe(pc));
+                break;
+            }
+        }
+        self.show_location();
+    }
+
+    /// Executes one instruction, reporting why the machine stopped if it did.
+    fn execute_one(&mut self) -> bool {
+        if self.stopped {
+            println!("The machine is stopped. Use 'set pc' to restart it");
+            return false;
+        }
+        match self.cpu.step() {
+            StepOutcome::Continued => true,
+            StepOutcome::Halted(arg) => {
+                println!("Machine halted (HM {:03X})", arg);
+                self.stopped = true;
+                false
+            }
+            StepOutcome::Exited(status) => {
+                println!("Program exited with status {}", status);
+                self.stopped = true;
+                false
+            }
+            StepOutcome::Break => {
+                println!("Break at {}", self.describe(self.cpu.last_site().pc));
+                false
+            }
+            StepOutcome::WaitingOnInput => {
+                let fault = MachineFault::ReadAfterEof(self.cpu.last_site());
+                println!("{}. Use 'in' to queue more input", fault);
+                false
+            }
+            StepOutcome::Faulted(fault) => {
+                println!("{}", fault);
+                self.stopped = true;
+                false
+            }
+        }
+    }
+
+    fn set(&mut self, target: &str, value: &str) -> Result<(), String> {
+        let value = parse_signed(value).ok_or_else(|| format!("Invalid value: {}", value))?;
+        match target {
+            "pc" => {
+                if !(0..=0xFFF).contains(&value) {
+                    return Err("PC must be between /000 and /FFF".to_string());
+                }
+                self.cpu.set_pc(value as u16);
+                self.stopped = false;
+            }
+            "ac" => {
+                if !(-128..=255).contains(&value) {
+                    return Err("AC must fit in a byte".to_string());
+                }
+                self.cpu.set_ac(value as i8);
+            }
+            _ => {
+                let address = self.resolve(target)?;
+                if !(-128..=255).contains(&value) {
+                    return Err("Memory values must fit in a byte".to_string());
+                }
+                match self.cpu.memory_mut().get_mut(address as usize) {
+                    Some(byte) => *byte = value as u8,
+                    None => return Err(format!("Address {:03X} is out of range", address)),
+                }
+            }
+        }
+        self.show_registers();
+        Ok(())
+    }
+
+    fn show_location(&self) {
+        self.show_registers();
+        if let Some(line) = self.cpu.context_window(self.cpu.pc(), 0).first() {
+            println!("{}", line);
+        }
+    }
+
+    fn show_registers(&self) {
+        let ac = self.cpu.ac();
+        println!(
+            "PC {}  AC {:02X} ({} in decimal)  {}",
+            self.describe(self.cpu.pc()),
+            ac,
+            ac,
+            self.cpu.flags()
+        );
+    }
+
+    fn list(&self, address: u16) {
+        for line in self.cpu.context_window(address, 5) {
+            println!("{}", line);
+        }
+    }
+
+    fn dump(&self, address: u16, len: u16) {
+        let memory = self.cpu.memory();
+        let end = (address as usize + len as usize).min(memory.len());
+        let start = (address as usize).min(end);
+        for (row, chunk) in memory[start..end].chunks(16).enumerate() {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&b| {
+                    if (0x20..0x7F).contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!(
+                "{:03X}: {:<47}  {}",
+                start + row * 16,
+                bytes.join(" "),
+                text
+            );
+        }
+    }
+
+    /// Turns an address or `LABEL[+N]` into an address.
+    fn resolve(&self, loc: &str) -> Result<u16, String> {
+        let (name, offset) = match loc.find('+') {
+            Some(idx) => (&loc[..idx], &loc[idx + 1..]),
+            None => (loc, "0"),
+        };
+        let offset = parse_number(offset).ok_or_else(|| format!("Invalid offset: {}", offset))?;
+        let base = match self.symbols.labels.get(name) {
+            Some(address) => *address,
+            None => parse_number(name).ok_or_else(|| format!("Unknown label: {}", name))?,
+        };
+        let address = base as usize + offset as usize;
+        if address >= self.cpu.memory().len() {
+            return Err(format!("Address {:03X} is out of range", address));
+        }
+        Ok(address as u16)
+    }
+
+    /// Formats an address with the closest label and source line, if known.
+    fn describe(&self, address: u16) -> String {
+        let mut description = format!("{:03X}", address);
+        if let Some(label) = self.symbols.label_for(address) {
+            description.push_str(&format!(" <{}>", label));
+        }
+        if let Some(position) = self.symbols.lines.get(&address) {
+            description.push_str(&format!(" {}", position));
+        }
+        description
+    }
+}
+
+fn parse_signed(word: &str) -> Option<i32> {
+    match word.strip_prefix('-') {
+        Some(rest) => parse_number(rest).map(|n| -(n as i32)),
+        None => parse_number(word).map(|n| n as i32),
+    }
+}
@@ -0,0 +1,193 @@
+use crate::number::parse_number;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Something `GD` reads from and `PD` writes to, picked by the operand of
+/// the instruction.
+pub trait IoDevice {
+    /// Next byte for `GD`, or `None` if there is nothing left to read.
+    fn read(&mut self) -> io::Result<Option<u8>>;
+
+    /// Takes the byte written by `PD`.
+    fn write(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Queues bytes to be read. Returns false if the device can't take them.
+    fn feed(&mut self, _bytes: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Builds a device from its description on the command line: `stdin`,
+/// `stdout`, `null`, `buffer` or `file:PATH`.
+pub fn open_device(spec: &str) -> Result<Box<dyn IoDevice>, String> {
+    match spec.split_once(':') {
+        Some(("file", path)) if !path.is_empty() => {
+            let device = FileDevice::open(path).map_err(|err| format!("{}: {}", path, err))?;
+            Ok(Box::new(device))
+        }
+        _ => match spec {
+            "stdin" => Ok(Box::new(StdinDevice)),
+            "stdout" => Ok(Box::new(StdoutDevice)),
+            "null" => Ok(Box::new(NullDevice)),
+            "buffer" => Ok(Box::new(BufferDevice::default())),
+            _ => Err(format!(
+                "Unknown device {}. Expected stdin, stdout, null, buffer or file:PATH",
+                spec
+            )),
+        },
+    }
+}
+
+/// Splits `N=DEVICE` into the device number, written as /1 or 1, and the
+/// device it names.
+pub(crate) fn parse_mapping(mapping: &str) -> Result<(u16, Box<dyn IoDevice>), String> {
+    let (number, spec) = mapping
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid device mapping {}. Expected N=DEVICE", mapping))?;
+    let number = parse_number(number)
+        .filter(|number| *number <= 0x0FFF)
+        .ok_or_else(|| format!("Invalid device number {}", number))?;
+    Ok((number, open_device(spec)?))
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, what)
+}
+
+/// Reads the standard input of the process. Can't be written to.
+pub struct StdinDevice;
+
+impl IoDevice for StdinDevice {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match io::stdin().read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write(&mut self, _: u8) -> io::Result<()> {
+        Err(unsupported("stdin can't be written to"))
+    }
+}
+
+/// Writes to the standard output of the process. Can't be read from.
+pub struct StdoutDevice;
+
+impl IoDevice for StdoutDevice {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        Err(unsupported("stdout can't be read from"))
+    }
+
+    fn write(&mut self, byte: u8) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte])?;
+        stdout.flush()
+    }
+}
+
+/// Reads what a file held when the machine started, and writes the file
+/// anew. The file is only created or truncated when the first byte is
+/// written, so a file that is only read is left as it is.
+pub struct FileDevice {
+    input: VecDeque<u8>,
+    path: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl FileDevice {
+    /// Reads from and writes to the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileDevice> {
+        let input = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        Ok(FileDevice::new(path, input))
+    }
+
+    /// Reads `input` and writes to the file at `path`.
+    pub fn new<P: AsRef<Path>>(path: P, input: Vec<u8>) -> FileDevice {
+        FileDevice {
+            input: input.into(),
+            path: path.as_ref().to_path_buf(),
+            file: None,
+        }
+    }
+}
+
+impl IoDevice for FileDevice {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write(&mut self, byte: u8) -> io::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                self.file.insert(fs::File::create(&self.path)?)
+            }
+        };
+        file.write_all(&[byte])
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> bool {
+        self.input.extend(bytes);
+        true
+    }
+}
+
+/// Bytes kept in memory. What `PD` writes is read back by `GD`, first in
+/// first out.
+#[derive(Debug, Default)]
+pub struct BufferDevice {
+    bytes: VecDeque<u8>,
+}
+
+impl BufferDevice {
+    pub fn new(bytes: &[u8]) -> BufferDevice {
+        BufferDevice {
+            bytes: bytes.iter().copied().collect(),
+        }
+    }
+
+    /// Bytes written and not yet read.
+    pub fn contents(&self) -> &VecDeque<u8> {
+        &self.bytes
+    }
+}
+
+impl IoDevice for BufferDevice {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.bytes.pop_front())
+    }
+
+    fn write(&mut self, byte: u8) -> io::Result<()> {
+        self.bytes.push_back(byte);
+        Ok(())
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> bool {
+        self.bytes.extend(bytes);
+        true
+    }
+}
+
+/// Always at the end of input, and throws away what is written.
+pub struct NullDevice;
+
+impl IoDevice for NullDevice {
+    fn read(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    fn write(&mut self, _: u8) -> io::Result<()> {
+        Ok(())
+    }
+}
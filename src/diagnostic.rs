@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fmt;
+
+/// The part of a source line an error refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    /// 1-based column of the first highlighted character.
+    pub column: usize,
+    pub len: usize,
+    /// Full text of the offending line.
+    pub text: String,
+    /// Name of the macro at the call site, when the line came from a macro.
+    pub expansion: Option<Box<Span>>,
+    /// Path in the `INCLUDE` that brought in `file`, if any.
+    pub included_from: Option<Box<Span>>,
+}
+
+impl Span {
+    /// Source location and caret lines, as in rustc output.
+    fn render(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", number, self.text)?;
+        // Tabs are kept so the caret lines up with the text above it
+        let padding: String = self
+            .text
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "{} | {}{}", gutter, padding, "^".repeat(self.len.max(1)))?;
+        let mut include = self.included_from.as_ref();
+        while let Some(site) = include {
+            writeln!(f, "  = note: included from {}:{}", site.file, site.line)?;
+            include = site.included_from.as_ref();
+        }
+        Ok(())
+    }
+}
+
+/// A problem found while assembling a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub message: String,
+    pub span: Option<Box<Span>>,
+    pub help: Option<String>,
+}
+
+impl AssembleError {
+    pub fn new(message: String, span: Option<Span>) -> AssembleError {
+        AssembleError {
+            message,
+            span: span.map(Box::new),
+            help: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: String) -> AssembleError {
+        self.help = Some(help);
+        self
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "error: {}", self.message)?;
+        if let Some(span) = &self.span {
+            span.render(f)?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(f, "  = help: {}", help)?;
+        }
+        let mut calls: Vec<&Span> = vec![];
+        let mut expansion = self.span.as_ref().and_then(|span| span.expansion.as_ref());
+        while let Some(call) = expansion {
+            calls.push(call);
+            expansion = call.expansion.as_ref();
+        }
+        // A macro calling itself would otherwise repeat the same note
+        for group in calls.chunk_by(|a, b| (a.line, a.column) == (b.line, b.column)) {
+            let call = group[0];
+            let name: String = call
+                .text
+                .chars()
+                .skip(call.column.saturating_sub(1))
+                .take(call.len)
+                .collect();
+            match group.len() {
+                1 => writeln!(f, "note: in expansion of macro `{}`", name)?,
+                n => writeln!(f, "note: in {} nested expansions of macro `{}`", n, name)?,
+            }
+            call.render(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for AssembleError {}
+
+/// Returns the candidate closest to `name`, if it is close enough to be a likely typo.
+pub fn closest_match<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let len = name.chars().count();
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, candidate)| {
+            // A word that shares nothing with a short name is still only a
+            // few edits away from it, so the distance must stay below both
+            let shortest = len.min(candidate.chars().count());
+            let threshold = (len.max(candidate.chars().count()) / 3).max(1);
+            *distance <= threshold && *distance < shortest
+        })
+        .min_by_key(|(distance, candidate)| (*distance, *candidate))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Optimal string alignment distance, ignoring case: the number of
+/// insertions, deletions, substitutions and swaps of adjacent characters
+/// that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_uppercase().chars().collect();
+    let b: Vec<char> = b.to_uppercase().chars().collect();
+    // distances[i][j] is the distance between the first i characters of a
+    // and the first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in distances[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapped_letters_are_one_edit() {
+        assert_eq!(edit_distance("STRAT", "START"), 1);
+        assert_eq!(closest_match("STRAT", ["START", "STOP"]), Some("START"));
+    }
+
+    #[test]
+    fn short_names_allow_two_edits() {
+        assert_eq!(closest_match("COUNT", ["COUNTER", "LOOP"]), Some("COUNTER"));
+        assert_eq!(closest_match("LOOP", ["COUNTER"]), None);
+    }
+
+    #[test]
+    fn unrelated_short_words_get_no_suggestion() {
+        let mnemonics = [
+            "JP", "JZ", "JN", "LV", "AD", "SB", "ML", "DV", "LD", "MM", "SC", "RS", "HM", "GD",
+            "PD", "OS", "K", "@", "#", "$",
+        ];
+        assert_eq!(closest_match("FOO", mnemonics), None);
+        assert_eq!(closest_match("LX", mnemonics), Some("LD"));
+    }
+
+    #[test]
+    fn case_is_ignored() {
+        assert_eq!(edit_distance("loop", "LOOP"), 0);
+    }
+}
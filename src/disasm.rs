@@ -0,0 +1,304 @@
+use crate::{Mnemonics, SymbolFile, Tape};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+
+/// Turns a tape back into assembly that the assembler accepts.
+pub struct Disassembler {
+    /// Contents of memory after loading. `None` where no block was loaded.
+    memory: Vec<Option<u8>>,
+    tape: Tape,
+    symbols: SymbolFile,
+    table: HashMap<u8, &'static str>,
+}
+
+impl Disassembler {
+    pub fn run(input: String, symbols_file: Option<String>) {
+        let bytes = fs::read(&input).unwrap_or_else(|err| {
+            eprintln!("Unable to read {}: {}", input, err);
+            std::process::exit(1);
+        });
+        let (tape, _) = Tape::parse(&bytes).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        let symbols = match symbols_file {
+            Some(file) => SymbolFile::load(&file).unwrap_or_else(|err| {
+                eprintln!("Unable to read symbols from {}: {}", file, err);
+                std::process::exit(1);
+            }),
+            None => SymbolFile::new(),
+        };
+        print!(
+            "; disassembled from {}\n{}",
+            input,
+            Disassembler::new(tape, symbols).disassemble()
+        );
+    }
+
+    pub fn new(tape: Tape, symbols: SymbolFile) -> Disassembler {
+        let mut memory = vec![None; 4096];
+        for block in tape.blocks.iter() {
+            for (idx, byte) in block.data.iter().enumerate() {
+                if let Some(cell) = memory.get_mut(block.origin as usize + idx) {
+                    *cell = Some(*byte);
+                }
+            }
+        }
+        Disassembler {
+            memory,
+            tape,
+            symbols,
+            table: Mnemonics::new().from_code,
+        }
+    }
+
+    pub fn disassemble(&self) -> String {
+        let code = self.find_code();
+        let labels = self.make_labels(&code);
+        let mut out = String::new();
+        for block in self.tape.blocks.iter() {
+            out.push_str(&format!("\n@ /{:03X}\n", block.origin));
+            let end = block.origin as usize + block.data.len();
+            let mut address = block.origin as usize;
+            while address < end {
+                if let Some(label) = labels.get(&(address as u16)) {
+                    out.push_str(&format!("{}\n", label));
+                }
+                let cell = self.memory.get(address).copied().flatten();
+                let next = self.memory.get(address + 1).copied().flatten();
+                match (cell, next) {
+                    (Some(msb), Some(lsb))
+                        if code.contains(&(address as u16)) && address + 1 < end =>
+                    {
+                        let text = self.instruction(msb, lsb, &code, &labels);
+                        out.push_str(&format!(
+                            "        {:<24}; {:03X}  {:02X}{:02X}\n",
+                            text, address, msb, lsb
+                        ));
+                        address += 2;
+                    }
+                    (Some(byte), _) => {
+                        let text = format!("K   {}", data_literal(byte));
+                        out.push_str(&format!(
+                            "        {:<24}; {:03X}  {:02X}\n",
+                            text, address, byte
+                        ));
+                        address += 1;
+                    }
+                    (None, _) => address += 1,
+                }
+            }
+        }
+        let entry = self.tape.entry_point();
+        out.push_str(&format!(
+            "\n        #   {}\n",
+            self.operand(entry, &code, &labels)
+        ));
+        out
+    }
+
+    /// Follows every path of execution from the entry point, returning the
+    /// address of each instruction reached.
+    fn find_code(&self) -> BTreeSet<u16> {
+        // Instructions rewritten by MM at run time can't be trusted to end a
+        // path, so keep going past them unless what follows is read as data
+        let mut modified = BTreeSet::new();
+        let mut data = BTreeSet::new();
+        loop {
+            let code = self.trace_paths(&modified, &data);
+            let mut new_modified = BTreeSet::new();
+            let mut new_data = BTreeSet::new();
+            for address in code.iter() {
+                match self.word(*address) {
+                    Some((9, arg)) => {
+                        new_modified.insert(arg);
+                        new_modified.insert(arg.wrapping_sub(1));
+                    }
+                    Some((4..=8, arg)) => {
+                        new_data.insert(arg);
+                    }
+                    _ => (),
+                }
+            }
+            if new_modified.is_subset(&modified) && new_data.is_subset(&data) {
+                return code;
+            }
+            modified.extend(new_modified);
+            data.extend(new_data);
+        }
+    }
+
+    fn trace_paths(&self, modified: &BTreeSet<u16>, data: &BTreeSet<u16>) -> BTreeSet<u16> {
+        let mut code = BTreeSet::new();
+        let mut pending = vec![self.tape.entry_point()];
+        while let Some(address) = pending.pop() {
+            if code.contains(&address) {
+                continue;
+            }
+            let (opcode, arg) = match self.word(address) {
+                Some(word) => word,
+                None => continue,
+            };
+            code.insert(address);
+            let next = address + 2;
+            if modified.contains(&address) && !data.contains(&next) {
+                pending.push(next);
+            }
+            match opcode {
+                0 => pending.push(arg),
+                1 | 2 => pending.extend(&[arg, next]),
+                10 => pending.extend(&[arg + 2, next]),
+                11 | 12 => (),
+                _ => pending.push(next),
+            }
+        }
+        code
+    }
+
+    /// Names every address that is jumped to, called or used as data.
+    fn make_labels(&self, code: &BTreeSet<u16>) -> BTreeMap<u16, String> {
+        let mut labels = BTreeMap::new();
+        for (name, address) in self.symbols.labels.iter() {
+            if self.loaded(*address) {
+                labels.insert(*address, name.clone());
+            }
+        }
+        let mut synthesize = |address: u16, prefix: &str| {
+            if !self.loaded(address)
+                || labels.contains_key(&address)
+                || self.symbol_offset(address).is_some()
+            {
+                return;
+            }
+            // Point into the middle of an instruction as LABEL+1 instead
+            if address > 0 && code.contains(&(address - 1)) {
+                return;
+            }
+            labels.insert(address, format!("{}{:03X}", prefix, address));
+        };
+        synthesize(self.tape.entry_point(), "L");
+        for address in code.iter() {
+            let (opcode, arg) = self.word(*address).unwrap();
+            match opcode {
+                0..=2 => synthesize(arg, "L"),
+                10 | 11 => synthesize(arg, "S"),
+                4..=9 => synthesize(arg, "D"),
+                _ => (),
+            }
+        }
+        // Operands pointing at the second byte of an instruction need its label
+        for address in code.iter() {
+            let (opcode, arg) = self.word(*address).unwrap();
+            let refers_to_memory = matches!(opcode, 0 | 1 | 2 | 4..=11);
+            if refers_to_memory
+                && arg > 0
+                && code.contains(&(arg - 1))
+                && !labels.contains_key(&arg)
+                && self.symbol_offset(arg).is_none()
+            {
+                labels
+                    .entry(arg - 1)
+                    .or_insert_with(|| format!("L{:03X}", arg - 1));
+            }
+        }
+        labels
+    }
+
+    fn instruction(
+        &self,
+        msb: u8,
+        lsb: u8,
+        code: &BTreeSet<u16>,
+        labels: &BTreeMap<u16, String>,
+    ) -> String {
+        let opcode = msb >> 4;
+        let arg = ((0x0F & msb as u16) << 8) + lsb as u16;
+        let mnemonic = self.table[&opcode].to_uppercase();
+        let operand = match opcode {
+            0 | 1 | 2 | 4..=11 => self.operand(arg, code, labels),
+            _ => format!("/{:03X}", arg),
+        };
+        format!("{:<4}{}", mnemonic, operand)
+    }
+
+    /// Names an address by its label, the label of the instruction it is
+    /// part of, the closest symbol before it, or its value in hex.
+    fn operand(
+        &self,
+        address: u16,
+        code: &BTreeSet<u16>,
+        labels: &BTreeMap<u16, String>,
+    ) -> String {
+        if let Some(label) = labels.get(&address) {
+            return label.clone();
+        }
+        if address > 0 && code.contains(&(address - 1)) {
+            if let Some(label) = labels.get(&(address - 1)) {
+                return format!("{}+1", label);
+            }
+        }
+        self.symbol_offset(address)
+            .unwrap_or_else(|| format!("/{:03X}", address))
+    }
+
+    /// Formats `address` relative to the closest label from the symbol file
+    /// that precedes it in the same block, e.g. `LDA0+1`.
+    fn symbol_offset(&self, address: u16) -> Option<String> {
+        let block = self.tape.blocks.iter().find(|block| {
+            block.origin <= address && (address as usize) < block.origin as usize + block.data.len()
+        })?;
+        self.symbols
+            .labels
+            .iter()
+            .filter(|(_, &a)| block.origin <= a && a < address)
+            .max_by_key(|(name, &a)| (a, std::cmp::Reverse(name.as_str())))
+            .map(|(name, &a)| format!("{}+{}", name, address - a))
+    }
+
+    fn loaded(&self, address: u16) -> bool {
+        matches!(self.memory.get(address as usize), Some(Some(_)))
+    }
+
+    fn word(&self, address: u16) -> Option<(u8, u16)> {
+        let msb = self.memory.get(address as usize).copied().flatten()?;
+        let lsb = self.memory.get(address as usize + 1).copied().flatten()?;
+        Some((msb >> 4, ((0x0F & msb as u16) << 8) + lsb as u16))
+    }
+}
+
+/// Printable characters are shown quoted, anything else in hex.
+fn data_literal(byte: u8) -> String {
+    if byte.is_ascii_graphic() && byte != b'"' && byte != b';' {
+        format!("\"{}\"", byte as char)
+    } else {
+        format!("/{:02X}", byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::Block;
+
+    fn program() -> Tape {
+        // LD /007, HM /000 and four bytes of data
+        let data = vec![0x80, 0x07, 0xC0, 0x00, 0x01, 0x02, 0x03, 0x04];
+        Tape::plain(0, vec![Block { origin: 0, data }])
+    }
+
+    #[test]
+    fn addresses_without_symbols_get_synthesized_labels() {
+        let out = Disassembler::new(program(), SymbolFile::new()).disassemble();
+        assert!(out.contains("LD  D007"), "{}", out);
+        assert!(out.contains("D007\n"), "{}", out);
+    }
+
+    #[test]
+    fn addresses_past_a_symbol_are_named_relative_to_it() {
+        let mut symbols = SymbolFile::new();
+        symbols.labels.insert("DATA".to_string(), 0x005);
+        let out = Disassembler::new(program(), symbols).disassemble();
+        assert!(out.contains("LD  DATA+2"), "{}", out);
+        assert!(!out.contains("D007"), "{}", out);
+    }
+}
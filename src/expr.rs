@@ -0,0 +1,371 @@
+/// A problem found while evaluating an expression. Offsets are in characters
+/// from the start of the expression text.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExprError {
+    pub message: String,
+    pub offset: usize,
+    pub len: usize,
+    /// Name of the symbol that could not be found, if that was the problem.
+    pub undefined: Option<String>,
+}
+
+/// Evaluates an operand expression.
+///
+/// Supports decimal, `/hex`, `0xhex`, `0bbinary` and `"c"` literals, labels,
+/// `$` for the address of the current statement, `HI(x)` and `LO(x)` for the
+/// high and low byte of an address, unary `-` and `!`, parentheses and the
+/// binary operators `* / % + - << >> < <= > >= == != & ^ | && ||` with the
+/// usual C precedence. Comparisons are 1 when true and 0 when false.
+pub(crate) fn evaluate(
+    text: &str,
+    location: u16,
+    lookup: &dyn Fn(&str) -> Option<i64>,
+) -> Result<i64, ExprError> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        location,
+        lookup,
+    };
+    let value = parser.expression(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        let rest: String = parser.chars[parser.pos..].iter().collect();
+        return Err(parser.error(
+            format!("unexpected `{}` after expression", rest.trim_end()),
+            parser.pos,
+            rest.trim_end().chars().count(),
+        ));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    location: u16,
+    lookup: &'a dyn Fn(&str) -> Option<i64>,
+}
+
+/// Binary operators, tightest binding last.
+const OPERATORS: &[(&str, u8)] = &[
+    ("||", 1),
+    ("&&", 2),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("==", 6),
+    ("!=", 6),
+    ("<", 7),
+    ("<=", 7),
+    (">", 7),
+    (">=", 7),
+    ("<<", 8),
+    (">>", 8),
+    ("+", 9),
+    ("-", 9),
+    ("*", 10),
+    ("/", 10),
+    ("%", 10),
+];
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: String, offset: usize, len: usize) -> ExprError {
+        ExprError {
+            message,
+            offset,
+            len: len.max(1),
+            undefined: None,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, token: &str) -> bool {
+        token
+            .chars()
+            .enumerate()
+            .all(|(idx, c)| self.chars.get(self.pos + idx) == Some(&c))
+    }
+
+    /// Parses operators binding tighter than `min_precedence`.
+    fn expression(&mut self, min_precedence: u8) -> Result<i64, ExprError> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let found = OPERATORS
+                .iter()
+                .filter(|(token, _)| self.starts_with(token))
+                .max_by_key(|(token, _)| token.len());
+            let (token, precedence) = match found {
+                Some((token, precedence)) if *precedence > min_precedence => (*token, *precedence),
+                _ => return Ok(lhs),
+            };
+            let start = self.pos;
+            self.pos += token.len();
+            let rhs = self.expression(precedence)?;
+            lhs = self.apply(token, lhs, rhs, start)?;
+        }
+    }
+
+    fn apply(&self, token: &str, lhs: i64, rhs: i64, offset: usize) -> Result<i64, ExprError> {
+        let overflow = || self.error("expression overflows".to_string(), offset, token.len());
+        match token {
+            "||" => Ok((lhs != 0 || rhs != 0) as i64),
+            "&&" => Ok((lhs != 0 && rhs != 0) as i64),
+            "==" => Ok((lhs == rhs) as i64),
+            "!=" => Ok((lhs != rhs) as i64),
+            "<" => Ok((lhs < rhs) as i64),
+            "<=" => Ok((lhs <= rhs) as i64),
+            ">" => Ok((lhs > rhs) as i64),
+            ">=" => Ok((lhs >= rhs) as i64),
+            "|" => Ok(lhs | rhs),
+            "^" => Ok(lhs ^ rhs),
+            "&" => Ok(lhs & rhs),
+            "<<" | ">>" if !(0..32).contains(&rhs) => {
+                Err(self.error(format!("cannot shift by {}", rhs), offset, token.len()))
+            }
+            "<<" => lhs.checked_shl(rhs as u32).ok_or_else(overflow),
+            ">>" => Ok(lhs >> rhs),
+            "+" => lhs.checked_add(rhs).ok_or_else(overflow),
+            "-" => lhs.checked_sub(rhs).ok_or_else(overflow),
+            "*" => lhs.checked_mul(rhs).ok_or_else(overflow),
+            "/" | "%" if rhs == 0 => {
+                Err(self.error("division by zero".to_string(), offset, token.len()))
+            }
+            "/" => Ok(lhs / rhs),
+            "%" => Ok(lhs % rhs),
+            _ => unreachable!(),
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError> {
+        self.skip_whitespace();
+        if self.peek() == Some('!') {
+            self.pos += 1;
+            return Ok((self.unary()? == 0) as i64);
+        }
+        if self.peek() == Some('-') {
+            let start = self.pos;
+            self.pos += 1;
+            let value = self.unary()?;
+            return value
+                .checked_neg()
+                .ok_or_else(|| self.error("expression overflows".to_string(), start, 1));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i64, ExprError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.peek() {
+            None => Err(self.error("expected a value".to_string(), start, 1)),
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expression(0)?;
+                self.expect(')', start)?;
+                Ok(value)
+            }
+            Some('$') => {
+                self.pos += 1;
+                Ok(self.location as i64)
+            }
+            Some('"') => self.character(),
+            Some('/') => {
+                self.pos += 1;
+                self.number(16, start)
+            }
+            Some('0') if self.starts_with("0x") || self.starts_with("0X") => {
+                self.pos += 2;
+                self.number(16, start)
+            }
+            Some('0') if self.starts_with("0b") || self.starts_with("0B") => {
+                self.pos += 2;
+                self.number(2, start)
+            }
+            Some(c) if c.is_ascii_digit() => self.number(10, start),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => self.name(),
+            Some(c) => Err(self.error(format!("unexpected `{}`", c), start, 1)),
+        }
+    }
+
+    fn expect(&mut self, c: char, open: usize) -> Result<(), ExprError> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return Ok(());
+        }
+        Err(self.error(format!("missing `{}`", c), open, self.pos - open))
+    }
+
+    fn number(&mut self, radix: u32, start: usize) -> Result<i64, ExprError> {
+        let digits_start = self.pos;
+        while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_alphanumeric() {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[digits_start..self.pos].iter().collect();
+        let text: String = self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&digits, radix).map_err(|_| {
+            let kind = match radix {
+                16 => "hexadecimal",
+                2 => "binary",
+                _ => "decimal",
+            };
+            self.error(
+                format!("`{}` is not a valid {} number", text, kind),
+                start,
+                self.pos - start,
+            )
+        })
+    }
+
+    fn character(&mut self) -> Result<i64, ExprError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut escaped = false;
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => break,
+                _ => escaped = false,
+            }
+        }
+        let literal: String = self.chars[start..self.pos].iter().collect();
+        let len = self.pos - start;
+        let bytes = crate::assembler::decode_string(&literal)
+            .map_err(|message| self.error(message, start, len))?;
+        match bytes.as_slice() {
+            [byte] => Ok(*byte as i64),
+            _ => Err(self.error(
+                format!("`{}` is not a single character", literal),
+                start,
+                len,
+            )),
+        }
+    }
+
+    fn name(&mut self) -> Result<i64, ExprError> {
+        let start = self.pos;
+        while self.pos < self.chars.len()
+            && (self.chars[self.pos].is_ascii_alphanumeric()
+                || self.chars[self.pos] == '_'
+                || self.chars[self.pos] == '.')
+        {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        self.skip_whitespace();
+        if self.peek() == Some('(') {
+            let open = self.pos;
+            self.pos += 1;
+            let value = self.expression(0)?;
+            self.expect(')', open)?;
+            return match name.to_uppercase().as_str() {
+                "HI" => Ok((value >> 8) & 0xFF),
+                "LO" => Ok(value & 0xFF),
+                _ => Err(self.error(
+                    format!("unknown function `{}`", name),
+                    start,
+                    name.chars().count(),
+                )),
+            };
+        }
+        match (self.lookup)(&name) {
+            Some(value) => Ok(value),
+            None => {
+                let mut error = self.error(
+                    format!("undefined symbol `{}`", name),
+                    start,
+                    name.chars().count(),
+                );
+                error.undefined = Some(name);
+                Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, ExprError> {
+        let lookup = |name: &str| match name {
+            "START" => Some(0x100),
+            "BUFFER" => Some(0x1F0),
+            _ => None,
+        };
+        evaluate(text, 0x123, &lookup)
+    }
+
+    #[test]
+    fn operators_follow_c_precedence() {
+        assert_eq!(eval("2 + 3 * 4"), Ok(14));
+        assert_eq!(eval("(2 + 3) * 4"), Ok(20));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("6 & 3 == 3"), Ok(0));
+        assert_eq!(eval("1 | 2 ^ 3 & 4"), Ok(3));
+        assert_eq!(eval("1 < 2 && 3 > 4 || 5 >= 5"), Ok(1));
+        assert_eq!(eval("-2 * -3"), Ok(6));
+        assert_eq!(eval("!0 + !7"), Ok(1));
+    }
+
+    #[test]
+    fn slash_is_hex_before_a_value_and_division_after_one() {
+        assert_eq!(eval("/1F0"), Ok(0x1F0));
+        assert_eq!(eval("/10 / 2"), Ok(8));
+        assert_eq!(eval("/10//2"), Ok(8));
+        assert_eq!(eval("BUFFER / /10"), Ok(0x1F));
+        assert_eq!(eval("7 % 4"), Ok(3));
+    }
+
+    #[test]
+    fn literals_and_symbols() {
+        assert_eq!(eval("0x1f + 0b101"), Ok(36));
+        assert_eq!(eval("\"A\""), Ok(65));
+        assert_eq!(eval("$ - START"), Ok(0x23));
+        let err = eval("START + MISSING").unwrap_err();
+        assert_eq!(err.undefined, Some("MISSING".to_string()));
+        assert_eq!((err.offset, err.len), (8, 7));
+    }
+
+    #[test]
+    fn hi_and_lo_split_an_address() {
+        assert_eq!(eval("HI(/1F0)"), Ok(0x01));
+        assert_eq!(eval("LO(/1F0)"), Ok(0xF0));
+        assert_eq!(eval("hi(BUFFER + /20)"), Ok(0x02));
+        assert_eq!(eval("HI(START) << 8 | LO(START)"), Ok(0x100));
+        assert!(eval("MID(START)").is_err());
+    }
+
+    #[test]
+    fn out_of_range_operations_are_errors() {
+        assert_eq!(eval("1 << 32").unwrap_err().message, "cannot shift by 32");
+        assert_eq!(eval("1 >> -1").unwrap_err().message, "cannot shift by -1");
+        assert_eq!(eval("1 / 0").unwrap_err().message, "division by zero");
+        assert_eq!(eval("5 % (1 - 1)").unwrap_err().message, "division by zero");
+        let err = eval("0x7FFFFFFFFFFFFFFF + 1").unwrap_err();
+        assert_eq!(err.message, "expression overflows");
+        assert_eq!(err.offset, 19);
+        assert!(eval("/1G").is_err());
+        assert!(eval("12AB").is_err());
+    }
+
+    #[test]
+    fn trailing_text_is_an_error() {
+        let err = eval("1 2").unwrap_err();
+        assert_eq!(err.message, "unexpected `2` after expression");
+        assert_eq!(eval("(1 + 2").unwrap_err().message, "missing `)`");
+    }
+}
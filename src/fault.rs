@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Where the machine was when a fault happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultSite {
+    /// Address of the instruction that faulted.
+    pub pc: u16,
+    pub opcode: u8,
+    pub operand: u16,
+}
+
+/// A bound on a run, set in `Config`, past which the CPU stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// Most instructions executed.
+    Instructions(u64),
+    /// Most bytes written by `PD` and `OS`.
+    OutputBytes(u64),
+    /// Longest time the machine runs for.
+    Time(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions(max) => write!(f, "Limit of {} instructions reached", max),
+            Limit::OutputBytes(max) => write!(f, "Limit of {} output bytes reached", max),
+            Limit::Time(timeout) => write!(f, "Time limit of {:?} reached", timeout),
+        }
+    }
+}
+
+/// Conditions that stop the CPU. Each class maps to its own process exit code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MachineFault {
+    /// `/` with a zero divisor in memory.
+    DivisionByZero(FaultSite),
+    /// The result of `+`, `-`, `*` or `/` does not fit in the accumulator,
+    /// in strict mode. Otherwise it wraps around and sets the overflow flag.
+    Overflow(FaultSite),
+    /// The instruction itself lies past the end of memory.
+    FetchOutOfRange(FaultSite),
+    /// A data access past the end of memory. Holds the offending address.
+    AddressOutOfRange(FaultSite, u16),
+    /// `OS` was called with a service that does not exist.
+    UnimplementedOsCall(FaultSite),
+    /// `GD` was executed after the input was exhausted.
+    ReadAfterEof(FaultSite),
+    /// `PD` could not write to its device.
+    OutputError(FaultSite, String),
+    /// `GD` could not read from its device.
+    InputError(FaultSite, String),
+    /// `GD` or `PD` selected a device number with no device. Holds the number.
+    UnmappedDevice(FaultSite, u16),
+    /// A limit on the run was reached before executing the instruction.
+    LimitReached(FaultSite, Limit),
+    /// `OS 0` was called with a negative exit status in AC.
+    BadExitStatus(FaultSite, i8),
+}
+
+impl MachineFault {
+    pub fn site(&self) -> FaultSite {
+        match self {
+            MachineFault::DivisionByZero(site)
+            | MachineFault::Overflow(site)
+            | MachineFault::FetchOutOfRange(site)
+            | MachineFault::AddressOutOfRange(site, _)
+            | MachineFault::UnimplementedOsCall(site)
+            | MachineFault::ReadAfterEof(site)
+            | MachineFault::OutputError(site, _)
+            | MachineFault::InputError(site, _)
+            | MachineFault::UnmappedDevice(site, _)
+            | MachineFault::LimitReached(site, _)
+            | MachineFault::BadExitStatus(site, _) => *site,
+        }
+    }
+
+    /// Process exit code used by the command line for this class of fault.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MachineFault::DivisionByZero(_) => 10,
+            MachineFault::Overflow(_) => 11,
+            MachineFault::FetchOutOfRange(_) => 12,
+            MachineFault::AddressOutOfRange(..) => 13,
+            MachineFault::UnimplementedOsCall(_) => 14,
+            MachineFault::ReadAfterEof(_) => 15,
+            MachineFault::OutputError(..) => 16,
+            MachineFault::InputError(..) => 17,
+            MachineFault::UnmappedDevice(..) => 18,
+            MachineFault::LimitReached(..) => 19,
+            MachineFault::BadExitStatus(..) => 20,
+        }
+    }
+}
+
+impl fmt::Display for MachineFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let site = self.site();
+        match self {
+            MachineFault::DivisionByZero(_) => write!(f, "Division by zero")?,
+            MachineFault::Overflow(_) => write!(f, "Accumulator overflow")?,
+            MachineFault::FetchOutOfRange(_) => write!(f, "Instruction fetch past end of memory")?,
+            MachineFault::AddressOutOfRange(_, address) => {
+                write!(f, "Access to address {:04X} past end of memory", address)?
+            }
+            MachineFault::UnimplementedOsCall(_) => write!(f, "Unimplemented OS call")?,
+            MachineFault::ReadAfterEof(_) => write!(f, "Trying to read after EOF")?,
+            MachineFault::OutputError(_, err) => write!(f, "Unable to write output: {}", err)?,
+            MachineFault::InputError(_, err) => write!(f, "Unable to read input: {}", err)?,
+            MachineFault::UnmappedDevice(_, number) => {
+                write!(f, "No device mapped to number {:X}", number)?
+            }
+            MachineFault::LimitReached(_, limit) => write!(f, "{}", limit)?,
+            MachineFault::BadExitStatus(_, status) => {
+                write!(f, "Exit status {} is negative", status)?
+            }
+        }
+        write!(
+            f,
+            " at {:03X} (opcode {:X}, operand {:03X})",
+            site.pc, site.opcode, site.operand
+        )
+    }
+}
+
+impl Error for MachineFault {}
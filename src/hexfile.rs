@@ -0,0 +1,285 @@
+use crate::number::{hex, unhex};
+use crate::tape::{checksum, from_memory};
+use crate::{MvnImage, Tape};
+
+/// How a program is written to a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /// The binary tape read by the loader.
+    Tape,
+    /// Text of the course's MVN simulator.
+    Mvn,
+    /// Intel HEX, with the entry point in a start segment address record.
+    IntelHex,
+    /// Motorola S-records, with the entry point in the S9 record.
+    SRecord,
+}
+
+impl ImageFormat {
+    /// Names of the formats, as given on the command line.
+    pub const NAMES: [&'static str; 4] = ["tape", "mvn", "ihex", "srec"];
+
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "tape" => Some(ImageFormat::Tape),
+            "mvn" => Some(ImageFormat::Mvn),
+            "ihex" => Some(ImageFormat::IntelHex),
+            "srec" => Some(ImageFormat::SRecord),
+            _ => None,
+        }
+    }
+
+    /// Writes `tape` out in this format.
+    pub fn write(self, tape: &Tape) -> Result<Vec<u8>, String> {
+        match self {
+            ImageFormat::Tape => tape.to_bytes().map_err(|err| err.to_string()),
+            ImageFormat::Mvn => Ok(MvnImage::from_tape(tape).to_text().into_bytes()),
+            ImageFormat::IntelHex => Ok(intel_hex(tape).into_bytes()),
+            ImageFormat::SRecord => Ok(s_records(tape).into_bytes()),
+        }
+    }
+
+    /// Reads a program written in this format.
+    pub fn read(self, bytes: &[u8]) -> Result<Tape, String> {
+        let text = || String::from_utf8(bytes.to_vec()).map_err(|err| err.to_string());
+        match self {
+            ImageFormat::Tape => Tape::parse(bytes)
+                .map(|(tape, _)| tape)
+                .map_err(|err| err.to_string()),
+            ImageFormat::Mvn => Ok(MvnImage::parse(&text()?)?.to_tape()),
+            ImageFormat::IntelHex => parse_intel_hex(&text()?),
+            ImageFormat::SRecord => parse_s_records(&text()?),
+        }
+    }
+}
+
+/// Most data bytes written on one line of Intel HEX or S-records.
+const LINE_BYTES: usize = 16;
+
+fn intel_hex(tape: &Tape) -> String {
+    let record = |kind: u8, address: u16, data: &[u8]| {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend(address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend(data);
+        bytes.push(checksum(&bytes));
+        format!(":{}\n", hex(&bytes))
+    };
+    let mut out = String::new();
+    for block in tape.blocks.iter() {
+        for (idx, data) in block.data.chunks(LINE_BYTES).enumerate() {
+            let address = block.origin + (idx * LINE_BYTES) as u16;
+            out.push_str(&record(0x00, address, data));
+        }
+    }
+    // CS:IP, with the whole entry point in IP
+    let start = [
+        0,
+        0,
+        (tape.entry_point() >> 8) as u8,
+        tape.entry_point() as u8,
+    ];
+    out.push_str(&record(0x03, 0, &start));
+    out.push_str(&record(0x01, 0, &[]));
+    out
+}
+
+fn parse_intel_hex(text: &str) -> Result<Tape, String> {
+    let mut memory = vec![None; 4096];
+    let mut entry = None;
+    for (idx, line) in text.lines().enumerate() {
+        let malformed = |why: &str| format!("Line {} {}: {}", idx + 1, why, line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(unhex)
+            .ok_or_else(|| malformed("is not an Intel HEX record"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(malformed("has the wrong length"));
+        }
+        if checksum(&bytes) != 0 {
+            return Err(malformed("does not match its checksum"));
+        }
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => place(&mut memory, address as usize, data).map_err(malformed)?,
+            0x01 => break,
+            0x02 | 0x04 if data.iter().all(|byte| *byte == 0) => (),
+            0x03 if data.len() == 4 => {
+                let segment = u16::from_be_bytes([data[0], data[1]]) as usize;
+                entry = Some(segment * 16 + u16::from_be_bytes([data[2], data[3]]) as usize);
+            }
+            0x05 if data.len() == 4 => {
+                entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize);
+            }
+            _ => return Err(malformed("is a record this memory cannot hold")),
+        }
+    }
+    start(&memory, entry)
+}
+
+fn s_records(tape: &Tape) -> String {
+    let record = |kind: char, address: u16, data: &[u8]| {
+        let mut bytes = vec![(data.len() + 3) as u8];
+        bytes.extend(address.to_be_bytes());
+        bytes.extend(data);
+        bytes.push(s_checksum(&bytes));
+        format!("S{}{}\n", kind, hex(&bytes))
+    };
+    let mut out = record('0', 0, b"sisprog");
+    let mut count = 0;
+    for block in tape.blocks.iter() {
+        for (idx, data) in block.data.chunks(LINE_BYTES).enumerate() {
+            let address = block.origin + (idx * LINE_BYTES) as u16;
+            out.push_str(&record('1', address, data));
+            count += 1;
+        }
+    }
+    out.push_str(&record('5', count, &[]));
+    out.push_str(&record('9', tape.entry_point(), &[]));
+    out
+}
+
+fn parse_s_records(text: &str) -> Result<Tape, String> {
+    let mut memory = vec![None; 4096];
+    let mut entry = None;
+    for (idx, line) in text.lines().enumerate() {
+        let malformed = |why: &str| format!("Line {} {}: {}", idx + 1, why, line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, bytes) = line
+            .strip_prefix('S')
+            .and_then(|rest| Some((rest.chars().next()?, unhex(rest.get(1..)?)?)))
+            .ok_or_else(|| malformed("is not an S-record"))?;
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(malformed("has the wrong length"));
+        }
+        if s_checksum(&bytes) != 0 {
+            return Err(malformed("does not match its checksum"));
+        }
+        // Address bytes of data and start records
+        let width = match kind {
+            '1' | '9' => 2,
+            '2' | '8' => 3,
+            '3' | '7' => 4,
+            '0' | '5' | '6' => continue,
+            _ => return Err(malformed("is not an S-record")),
+        };
+        if bytes.len() < width + 2 {
+            return Err(malformed("has the wrong length"));
+        }
+        let address = bytes[1..=width]
+            .iter()
+            .fold(0, |address, byte| (address << 8) | *byte as usize);
+        match kind {
+            '1' | '2' | '3' => {
+                let data = &bytes[width + 1..bytes.len() - 1];
+                place(&mut memory, address, data).map_err(malformed)?;
+            }
+            _ => entry = Some(address),
+        }
+    }
+    start(&memory, entry)
+}
+
+/// Copies `data` to `address`, if it fits in memory.
+fn place(memory: &mut [Option<u8>], address: usize, data: &[u8]) -> Result<(), &'static str> {
+    let cells = memory
+        .get_mut(address..address + data.len())
+        .ok_or("runs past the end of memory")?;
+    for (cell, byte) in cells.iter_mut().zip(data) {
+        *cell = Some(*byte);
+    }
+    Ok(())
+}
+
+/// The tape that loads `memory` and jumps to `entry`.
+fn start(memory: &[Option<u8>], entry: Option<usize>) -> Result<Tape, String> {
+    match entry {
+        Some(entry) if entry < 0x1000 => Ok(from_memory(memory, entry as u16)),
+        Some(entry) => Err(format!("Entry point {:X} is past the end of memory", entry)),
+        None => Err("No start record gives the entry point".to_string()),
+    }
+}
+
+/// The ones' complement of the sum of `bytes`, which is 0 for a whole
+/// S-record.
+fn s_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    /// A program with a block over two lines of records and one at the
+    /// very end of memory.
+    fn program() -> Tape {
+        let mut memory = vec![None; 4096];
+        for (idx, cell) in memory[0x100..0x114].iter_mut().enumerate() {
+            *cell = Some(idx as u8);
+        }
+        memory[0xFFE] = Some(0xC0);
+        memory[0xFFF] = Some(0x00);
+        from_memory(&memory, 0x100)
+    }
+
+    #[test]
+    fn intel_hex_round_trips() {
+        let tape = program();
+        let bytes = ImageFormat::IntelHex.write(&tape).unwrap();
+        assert_eq!(ImageFormat::IntelHex.read(&bytes), Ok(tape));
+    }
+
+    #[test]
+    fn s_records_round_trip() {
+        let tape = program();
+        let bytes = ImageFormat::SRecord.write(&tape).unwrap();
+        assert_eq!(ImageFormat::SRecord.read(&bytes), Ok(tape));
+    }
+
+    #[test]
+    fn records_are_written_with_checksums() {
+        let tape = Tape::plain(
+            0x100,
+            vec![Block {
+                origin: 0x100,
+                data: vec![0x01, 0x20],
+            }],
+        );
+        assert_eq!(
+            intel_hex(&tape),
+            ":020100000120DC\n:0400000300000100F8\n:00000001FF\n"
+        );
+        let srec = s_records(&tape);
+        let lines: Vec<&str> = srec.lines().skip(1).collect();
+        assert_eq!(lines, ["S10501000120D8", "S5030001FB", "S9030100FB"]);
+    }
+
+    #[test]
+    fn bad_checksum_is_an_error() {
+        let err = parse_intel_hex(":020100000120DD\n:00000001FF\n").unwrap_err();
+        assert_eq!(err, "Line 1 does not match its checksum: :020100000120DD");
+        let err = parse_s_records("S10501000120D9\nS9030100FB\n").unwrap_err();
+        assert_eq!(err, "Line 1 does not match its checksum: S10501000120D9");
+    }
+
+    #[test]
+    fn data_past_memory_is_an_error() {
+        let err = parse_intel_hex(":021000000120CD\n:00000001FF\n").unwrap_err();
+        assert!(err.contains("runs past the end of memory"), "{}", err);
+    }
+
+    #[test]
+    fn missing_entry_point_is_an_error() {
+        let err = parse_s_records("S10501000120D8\n").unwrap_err();
+        assert_eq!(err, "No start record gives the entry point");
+    }
+}
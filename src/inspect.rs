@@ -0,0 +1,360 @@
+use crate::tape::{checksum, CHECKSUMS, EXTENDED_MAGIC, RELOCATABLE};
+use crate::Tape;
+use std::fmt;
+use std::fs;
+
+/// Addresses taken by loader.asm, which a tape must not load over.
+const LOADER_REGION: (u16, u16) = (0x000, 0x048);
+
+/// Something about a tape that keeps it from loading as intended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The tape ended at this offset while `expected` was being read.
+    UnexpectedEof { offset: usize, expected: String },
+    /// Bytes after the last record, which the program reads as input.
+    TrailingBytes { offset: usize, count: usize },
+    /// The extended header has flags this version does not know.
+    UnknownFlags(u8),
+    /// A record on a plain tape has length 0, which the loader takes as 256.
+    EmptyRecord(usize),
+    /// A record does not add up to its checksum.
+    BadChecksum(usize),
+    /// A record runs past the last address in memory.
+    OutOfMemory { record: usize, end: usize },
+    /// Two records load over the same addresses.
+    Overlap { first: usize, second: usize },
+    /// A record loads over the loader while it runs.
+    OverwritesLoader(usize),
+    /// The instruction the loader jumps through is not a `JP`.
+    EntryNotJump(u16),
+    /// No record loads the entry point.
+    EntryNotLoaded(u16),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnexpectedEof { offset, expected } => {
+                write!(f, "tape ends at byte {} while reading {}", offset, expected)
+            }
+            Problem::TrailingBytes { offset, count } => write!(
+                f,
+                "{} byte(s) after the last record, from byte {}, will be read as program input",
+                count, offset
+            ),
+            Problem::UnknownFlags(flags) => write!(f, "unknown flags {:02X} in header", flags),
+            Problem::EmptyRecord(record) => write!(
+                f,
+                "record {} has length 0, which the loader reads as 256",
+                record + 1
+            ),
+            Problem::BadChecksum(record) => {
+                write!(f, "record {} does not match its checksum", record + 1)
+            }
+            Problem::OutOfMemory { record, end } => write!(
+                f,
+                "record {} ends at {:03X}, past the end of memory",
+                record + 1,
+                end
+            ),
+            Problem::Overlap { first, second } => {
+                write!(f, "record {} overlaps record {}", second + 1, first + 1)
+            }
+            Problem::OverwritesLoader(record) => {
+                write!(f, "record {} loads over the loader", record + 1)
+            }
+            Problem::EntryNotJump(entry) => {
+                write!(f, "entry instruction {:04X} is not a JP", entry)
+            }
+            Problem::EntryNotLoaded(address) => {
+                write!(f, "entry point {:03X} is not loaded by any record", address)
+            }
+        }
+    }
+}
+
+/// One record of a tape, as the loader reads it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Where the record starts in the tape.
+    pub offset: usize,
+    pub origin: u16,
+    /// Bytes the loader places, which is 256 for a length byte of 0.
+    pub len: usize,
+}
+
+/// A tape decoded byte by byte, the way loader.asm reads it, with every
+/// problem found along the way.
+pub struct Inspection {
+    /// Flags of an extended tape, `None` for a plain one.
+    pub flags: Option<u8>,
+    pub entry: Option<u16>,
+    pub records: Vec<Record>,
+    pub problems: Vec<Problem>,
+    /// Number of bytes in the tape.
+    size: usize,
+    /// How many records the header announces.
+    count: Option<usize>,
+}
+
+impl Inspection {
+    pub fn run(input: String, loader: Option<String>) {
+        let bytes = fs::read(&input).unwrap_or_else(|err| {
+            eprintln!("Unable to read {}: {}", input, err);
+            std::process::exit(1);
+        });
+        let loader = match loader {
+            Some(loader) => {
+                let bytes = fs::read(&loader).unwrap_or_else(|err| {
+                    eprintln!("Unable to read {}: {}", loader, err);
+                    std::process::exit(1);
+                });
+                let (tape, _) = Tape::parse(&bytes).unwrap_or_else(|err| {
+                    eprintln!("{}: {}", loader, err);
+                    std::process::exit(1);
+                });
+                tape.blocks
+                    .iter()
+                    .map(|block| (block.origin, block.origin + block.data.len() as u16))
+                    .collect()
+            }
+            None => vec![LOADER_REGION],
+        };
+        let inspection = Inspection::new(&bytes, &loader);
+        print!("{}: {}", input, inspection);
+        if !inspection.problems.is_empty() {
+            std::process::exit(1);
+        }
+    }
+
+    /// Decodes `bytes`. `loader` holds the start and end of each region of
+    /// memory the loader takes.
+    pub fn new(bytes: &[u8], loader: &[(u16, u16)]) -> Inspection {
+        let mut inspection = Inspection {
+            flags: None,
+            entry: None,
+            records: vec![],
+            problems: vec![],
+            size: bytes.len(),
+            count: None,
+        };
+        inspection.decode(bytes);
+        inspection.check(loader);
+        inspection
+    }
+
+    /// Reads the tape the way the loader does, stopping where it runs out.
+    fn decode(&mut self, bytes: &[u8]) {
+        let mut cursor = Cursor { bytes, offset: 0 };
+        if let Err(problem) = self.read_records(&mut cursor) {
+            self.problems.push(problem);
+        } else if cursor.offset < bytes.len() {
+            self.problems.push(Problem::TrailingBytes {
+                offset: cursor.offset,
+                count: bytes.len() - cursor.offset,
+            });
+        }
+    }
+
+    fn read_records(&mut self, cursor: &mut Cursor) -> Result<(), Problem> {
+        let mut flags = 0;
+        if cursor.bytes.starts_with(EXTENDED_MAGIC) {
+            cursor.read(EXTENDED_MAGIC.len(), || "the header".to_string())?;
+            flags = cursor.read(1, || "the header flags".to_string())?[0];
+            self.flags = Some(flags);
+            if flags & !(RELOCATABLE | CHECKSUMS) != 0 {
+                self.problems.push(Problem::UnknownFlags(flags));
+            }
+        }
+        let count = match cursor.read(1, || "the record count".to_string())?[0] {
+            // The loader only checks for the last record after loading one
+            0 if self.flags.is_none() => 256,
+            count => count as usize,
+        };
+        self.count = Some(count);
+        let entry = cursor.read(2, || "the entry instruction".to_string())?;
+        self.entry = Some(u16::from_be_bytes([entry[0], entry[1]]));
+        for record in 0..count {
+            let expected = |field: &str| format!("the {} of record {}", field, record + 1);
+            let offset = cursor.offset;
+            let header = cursor.read(3, || expected("header"))?;
+            let origin = u16::from_be_bytes([header[0], header[1]]);
+            let len = match header[2] {
+                // The loader only checks for the end after loading a byte
+                0 if self.flags.is_none() => 256,
+                len => len as usize,
+            };
+            if header[2] == 0 && self.flags.is_none() {
+                self.problems.push(Problem::EmptyRecord(record));
+            }
+            self.records.push(Record {
+                offset,
+                origin,
+                len,
+            });
+            cursor.read(len, || expected("data"))?;
+            if flags & RELOCATABLE != 0 {
+                cursor.read(len.div_ceil(8), || expected("relocation bits"))?;
+            }
+            if flags & CHECKSUMS != 0 {
+                cursor.read(1, || expected("checksum"))?;
+                if checksum(&cursor.bytes[offset..cursor.offset]) != 0 {
+                    self.problems.push(Problem::BadChecksum(record));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks for records that cannot all be loaded as they are.
+    fn check(&mut self, loader: &[(u16, u16)]) {
+        let range = |record: &Record| (record.origin as usize, record.origin as usize + record.len);
+        let overlaps = |(start, end): (usize, usize), (other_start, other_end): (usize, usize)| {
+            start < other_end && other_start < end
+        };
+        for (idx, record) in self.records.iter().enumerate() {
+            let (start, end) = range(record);
+            if end > 0x1000 {
+                self.problems
+                    .push(Problem::OutOfMemory { record: idx, end });
+            }
+            for (first, other) in self.records[..idx].iter().enumerate() {
+                if overlaps((start, end), range(other)) {
+                    self.problems.push(Problem::Overlap { first, second: idx });
+                }
+            }
+            // Extended tapes are loaded by the CPU, with no loader in memory
+            let loader_hit = loader
+                .iter()
+                .any(|(from, to)| overlaps((start, end), (*from as usize, *to as usize)));
+            if self.flags.is_none() && loader_hit {
+                self.problems.push(Problem::OverwritesLoader(idx));
+            }
+        }
+        if let Some(entry) = self.entry {
+            if entry >> 12 != 0 {
+                self.problems.push(Problem::EntryNotJump(entry));
+            }
+            let address = (entry & 0x0FFF) as usize;
+            let complete = Some(self.records.len()) == self.count;
+            let loaded = self.records.iter().any(|record| {
+                let (start, end) = range(record);
+                (start..end).contains(&address)
+            });
+            if complete && !loaded {
+                self.problems.push(Problem::EntryNotLoaded(address as u16));
+            }
+        }
+    }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes, ", self.size)?;
+        match self.flags {
+            None => writeln!(f, "plain tape for the loader")?,
+            Some(flags) => {
+                let mut features = vec![];
+                if flags & RELOCATABLE != 0 {
+                    features.push("relocatable");
+                }
+                if flags & CHECKSUMS != 0 {
+                    features.push("checksums");
+                }
+                writeln!(
+                    f,
+                    "extended tape ({}) for `cpu --load-address`",
+                    features.join(", ")
+                )?;
+            }
+        }
+        if let Some(entry) = self.entry {
+            writeln!(
+                f,
+                "entry    {:04X} (jumps to {:03X})",
+                entry,
+                entry & 0x0FFF
+            )?;
+        }
+        if let Some(count) = self.count {
+            writeln!(f, "records  {} of {} read", self.records.len(), count)?;
+        }
+        if !self.records.is_empty() {
+            writeln!(f, "     #  OFFSET  ORIGIN  END  LENGTH")?;
+        }
+        for (idx, record) in self.records.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>6}  {:>6}  {:03X}     {:03X}  {:>6}",
+                idx + 1,
+                record.offset,
+                record.origin,
+                (record.origin as usize + record.len).saturating_sub(1),
+                record.len
+            )?;
+        }
+        for problem in self.problems.iter() {
+            writeln!(f, "error: {}", problem)?;
+        }
+        match self.problems.len() {
+            0 => writeln!(f, "no problems found"),
+            count => writeln!(f, "{} problem(s) found", count),
+        }
+    }
+}
+
+/// Reads a tape front to back.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Takes the next `len` bytes, or says that the tape ended while reading
+    /// what `expected` describes.
+    fn read(&mut self, len: usize, expected: impl Fn() -> String) -> Result<&'a [u8], Problem> {
+        let field = self.bytes.get(self.offset..self.offset + len);
+        let field = field.ok_or_else(|| Problem::UnexpectedEof {
+            offset: self.bytes.len(),
+            expected: expected(),
+        })?;
+        self.offset += len;
+        Ok(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_zero_is_read_as_256_records() {
+        let bytes = [0, 0x01, 0x00, 0x01, 0x00, 2, 0xC0, 0x00];
+        let inspection = Inspection::new(&bytes, &[LOADER_REGION]);
+        assert_eq!(inspection.count, Some(256));
+        assert_eq!(inspection.records.len(), 1);
+        assert_eq!(
+            inspection.problems,
+            vec![Problem::UnexpectedEof {
+                offset: 8,
+                expected: "the header of record 2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn empty_record_is_only_a_problem_for_the_loader() {
+        let plain = [1, 0x01, 0x00, 0x01, 0x00, 0];
+        let inspection = Inspection::new(&plain, &[LOADER_REGION]);
+        assert!(inspection.problems.contains(&Problem::EmptyRecord(0)));
+
+        let mut extended = EXTENDED_MAGIC.to_vec();
+        extended.extend([CHECKSUMS, 2, 0x01, 0x00, 0x01, 0x00, 2, 0xC0, 0x00]);
+        extended.push(checksum(&extended[7..]));
+        extended.extend([0x02, 0x00, 0]);
+        extended.push(checksum(&extended[13..]));
+        let inspection = Inspection::new(&extended, &[LOADER_REGION]);
+        assert_eq!(inspection.records.len(), 2);
+        assert_eq!(inspection.problems, vec![]);
+    }
+}
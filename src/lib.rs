@@ -1,12 +1,45 @@
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 mod assembler;
 mod cpu;
+mod debugger;
+mod device;
+mod diagnostic;
+mod disasm;
+mod expr;
+mod fault;
+mod hexfile;
+mod inspect;
+mod linker;
+mod macros;
+mod mvn;
+mod number;
+mod object;
+mod profile;
+mod supervisor;
+mod symfile;
+mod tape;
 
-pub use crate::assembler::Assembler;
-pub use crate::cpu::{Config, CPU};
+pub use crate::assembler::{Assembler, AssemblerConfig};
+pub use crate::cpu::{Config, Flags, StepOutcome, CPU};
+pub use crate::debugger::Debugger;
+pub use crate::device::{
+    open_device, BufferDevice, FileDevice, IoDevice, NullDevice, StdinDevice, StdoutDevice,
+};
+pub use crate::diagnostic::{AssembleError, Span};
+pub use crate::disasm::Disassembler;
+pub use crate::fault::{FaultSite, Limit, MachineFault};
+pub use crate::hexfile::ImageFormat;
+pub use crate::inspect::{Inspection, Problem, Record};
+pub use crate::linker::{LinkError, Linker};
+pub use crate::mvn::MvnImage;
+pub use crate::object::Object;
+pub use crate::profile::{Profile, ProfileFormat, ProfileOutput, Subroutine};
+pub use crate::supervisor::OsService;
+pub use crate::symfile::{SourceLine, SymbolFile};
+pub use crate::tape::{Block, Tape, TapeError};
 
 #[derive(Default)]
 pub struct Mnemonics<'a> {
@@ -52,6 +85,12 @@ impl<'a> Mnemonics<'a> {
 #[derive(Debug)]
 struct Symbols {
     table: HashMap<String, u16>,
+    /// Names in `table` that are labels defined by the program.
+    labels: HashSet<String>,
+    /// Values bound with `EQU`, `SET` or on the command line.
+    constants: HashMap<String, i64>,
+    /// Constants bound with `SET`, which may be bound again.
+    variables: HashSet<String>,
 }
 
 impl Symbols {
@@ -76,18 +115,92 @@ impl Symbols {
         table.insert("@".to_string(), 16);
         table.insert("#".to_string(), 17);
         table.insert("K".to_string(), 18);
+        table.insert("STR".to_string(), 19);
+        table.insert("STRZ".to_string(), 20);
+        table.insert("PSTR".to_string(), 21);
+        table.insert("EQU".to_string(), 22);
+        table.insert("SET".to_string(), 23);
+        table.insert("$".to_string(), 24);
+        table.insert("IF".to_string(), 25);
+        table.insert("IFDEF".to_string(), 26);
+        table.insert("IFNDEF".to_string(), 27);
+        table.insert("ELSE".to_string(), 28);
+        table.insert("ENDIF".to_string(), 29);
+        table.insert(">".to_string(), 30);
+        table.insert("<".to_string(), 31);
 
-        Symbols { table }
+        Symbols {
+            table,
+            labels: HashSet::new(),
+            constants: HashMap::new(),
+            variables: HashSet::new(),
+        }
+    }
+
+    /// Defines a label. Returns false if the name is already taken.
+    pub fn insert(&mut self, key: &str, val: u16) -> bool {
+        if self.table.contains_key(key) || self.constants.contains_key(key) {
+            return false;
+        }
+        self.table.insert(key.to_string(), val);
+        self.labels.insert(key.to_string());
+        true
+    }
+
+    /// Binds a constant. Returns false if the name is taken by anything other
+    /// than a redefinable constant.
+    pub fn define(&mut self, key: &str, value: i64, redefinable: bool) -> bool {
+        let taken = self.table.contains_key(key)
+            || (self.constants.contains_key(key) && !self.variables.contains(key));
+        if taken || (!redefinable && self.variables.contains(key)) {
+            return false;
+        }
+        if redefinable {
+            self.variables.insert(key.to_string());
+        }
+        self.constants.insert(key.to_string(), value);
+        true
+    }
+
+    /// Unbinds every constant defined with `SET`.
+    pub fn forget_variables(&mut self) {
+        for key in self.variables.drain() {
+            self.constants.remove(&key);
+        }
+    }
+
+    /// Looks up a label or a constant.
+    pub fn value(&self, key: &str) -> Option<i64> {
+        match self.constants.get(key) {
+            Some(value) => Some(*value),
+            None => self.label(key).map(|address| address as i64),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<u16> {
+        self.table.get(key).copied()
+    }
+
+    /// Looks up a label, ignoring instructions.
+    pub fn label(&self, key: &str) -> Option<u16> {
+        if !self.labels.contains(key) {
+            return None;
+        }
+        self.get(key)
     }
 
-    pub fn insert(&mut self, key: &str, val: u16) {
-        if self.table.insert(key.to_string(), val).is_some() {
-            eprintln!("Label {} already previously found in symbols table", key);
-            std::process::exit(1);
+    /// Looks up an instruction or pseudo instruction, ignoring labels.
+    pub fn opcode(&self, key: &str) -> Option<u16> {
+        if self.labels.contains(key) {
+            return None;
         }
+        self.get(key)
     }
 
-    pub fn get(&self, key: &str) -> u16 {
-        *self.table.get(key).unwrap()
+    pub fn mnemonics(&self) -> impl Iterator<Item = &str> {
+        self.table
+            .keys()
+            .filter(move |key| !self.labels.contains(*key))
+            .map(|key| key.as_str())
     }
 }
@@ -0,0 +1,319 @@
+use crate::number::parse_number;
+use crate::tape::relocate;
+use crate::{Object, Tape, TapeError};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+/// A problem found while combining objects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    /// Two modules export the same name.
+    DuplicateExport {
+        name: String,
+        first: String,
+        second: String,
+    },
+    /// A module imports a name that no module exports.
+    Undefined { name: String, module: String },
+    /// A module would end past the last address in memory.
+    OutOfMemory { module: String, end: usize },
+    /// A relocated operand no longer fits in 12 bits.
+    OperandOutOfRange { module: String, address: u16 },
+    /// A relocation points outside every block of its module.
+    BadRelocation { module: String, address: u16 },
+    /// Two modules would load bytes at the same address.
+    Overlap {
+        first: String,
+        second: String,
+        address: u16,
+    },
+    /// No module has an entry point.
+    NoEntry,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateExport {
+                name,
+                first,
+                second,
+            } => write!(f, "`{}` is exported by both {} and {}", name, first, second),
+            LinkError::Undefined { name, module } => {
+                write!(f, "`{}` is imported by {} but never exported", name, module)
+            }
+            LinkError::OutOfMemory { module, end } => {
+                write!(f, "{} ends at {:03X}, past the end of memory", module, end)
+            }
+            LinkError::OperandOutOfRange { module, address } => write!(
+                f,
+                "operand of the instruction at {:03X} in {} no longer fits in 12 bits",
+                address, module
+            ),
+            LinkError::BadRelocation { module, address } => write!(
+                f,
+                "{} relocates {:03X}, which is not part of the module",
+                module, address
+            ),
+            LinkError::Overlap {
+                first,
+                second,
+                address,
+            } => write!(f, "{} overlaps {} at {:03X}", second, first, address),
+            LinkError::NoEntry => write!(f, "no module has an entry point"),
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+/// Places relocatable objects one after another in memory and resolves the
+/// symbols they share, producing a tape for the loader.
+#[derive(Default)]
+pub struct Linker {
+    /// Each module's name and contents, in placement order.
+    modules: Vec<(String, Object)>,
+}
+
+impl Linker {
+    pub fn run(output: String, inputs: Vec<String>, origin: Option<String>) {
+        let origin = match origin {
+            Some(origin) => parse_number(&origin).unwrap_or_else(|| {
+                eprintln!("Invalid origin {}", origin);
+                std::process::exit(1);
+            }),
+            None => 0x100,
+        };
+        let mut linker = Linker::new();
+        for input in inputs {
+            let object = Object::load(&input).unwrap_or_else(|err| {
+                eprintln!("Unable to read {}: {}", input, err);
+                std::process::exit(1);
+            });
+            linker.add(input, object);
+        }
+        let tape = linker.link(origin).unwrap_or_else(|errors| {
+            for error in errors.iter() {
+                eprintln!("error: {}", error);
+            }
+            eprintln!("Could not link due to {} error(s)", errors.len());
+            std::process::exit(1);
+        });
+        let bytes = tape.to_bytes().unwrap_or_else(|err| {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        });
+        if let Err(err) = fs::write(&output, bytes) {
+            eprintln!("Unable to write {}: {}", output, err);
+            std::process::exit(1);
+        }
+    }
+
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    pub fn add(&mut self, name: String, object: Object) {
+        self.modules.push((name, object));
+    }
+
+    /// Places the modules from `origin` up. The entry point is that of the
+    /// first module that has one.
+    pub fn link(&self, origin: u16) -> Result<Tape, Vec<LinkError>> {
+        let mut errors = vec![];
+        let mut bases = vec![];
+        let mut next = origin as usize;
+        for (name, object) in self.modules.iter() {
+            bases.push(next as u16);
+            next += object.size as usize;
+            if next > 0x1000 {
+                errors.push(LinkError::OutOfMemory {
+                    module: name.clone(),
+                    end: next,
+                });
+                return Err(errors);
+            }
+        }
+        // Every module now starts in memory, but a hand-written object may
+        // still place something past its own size
+        let place = |module: &str, base: u16, address: u16, len: usize| {
+            let end = base as usize + address as usize + len;
+            match end {
+                end if end > 0x1000 => Err(LinkError::OutOfMemory {
+                    module: module.to_string(),
+                    end,
+                }),
+                _ => Ok(base + address),
+            }
+        };
+        let mut exports: HashMap<&str, (u16, &str)> = HashMap::new();
+        for ((name, object), base) in self.modules.iter().zip(bases.iter()) {
+            for (symbol, address) in object.exports.iter() {
+                if let Some((_, first)) = exports.get(symbol.as_str()) {
+                    errors.push(LinkError::DuplicateExport {
+                        name: symbol.clone(),
+                        first: first.to_string(),
+                        second: name.clone(),
+                    });
+                    continue;
+                }
+                match place(name, *base, *address, 1) {
+                    Ok(address) => {
+                        exports.insert(symbol, (address, name));
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut blocks = vec![];
+        let mut spans = vec![];
+        let mut entry = None;
+        for ((name, object), base) in self.modules.iter().zip(bases.iter()) {
+            let mut module = object.blocks.clone();
+            let mut fixups: Vec<(u16, u16)> = object
+                .relocations
+                .iter()
+                .map(|address| (*address, *base))
+                .collect();
+            for symbol in object.imports.iter() {
+                if !exports.contains_key(symbol.as_str()) {
+                    errors.push(LinkError::Undefined {
+                        name: symbol.clone(),
+                        module: name.clone(),
+                    });
+                }
+            }
+            for (address, symbol) in object.externals.iter() {
+                if let Some((target, _)) = exports.get(symbol.as_str()) {
+                    fixups.push((*address, *target));
+                }
+            }
+            for (address, offset) in fixups {
+                match relocate(&mut module, address, offset) {
+                    Err(TapeError::OperandOutOfRange(address)) => {
+                        errors.push(LinkError::OperandOutOfRange {
+                            module: name.clone(),
+                            address,
+                        })
+                    }
+                    Err(_) => errors.push(LinkError::BadRelocation {
+                        module: name.clone(),
+                        address,
+                    }),
+                    Ok(()) => (),
+                }
+            }
+            for mut block in module {
+                match place(name, *base, block.origin, block.data.len()) {
+                    Ok(origin) => block.origin = origin,
+                    Err(err) => {
+                        errors.push(err);
+                        continue;
+                    }
+                }
+                spans.push((block.origin, block.data.len(), name));
+                blocks.push(block);
+            }
+            if entry.is_none() {
+                entry = match object.entry.map(|address| place(name, *base, address, 1)) {
+                    Some(Ok(address)) => Some(address),
+                    Some(Err(err)) => {
+                        errors.push(err);
+                        None
+                    }
+                    None => None,
+                };
+            }
+        }
+        spans.sort();
+        for pair in spans.windows(2) {
+            let ((origin, len, first), (next, _, second)) = (pair[0], pair[1]);
+            if origin as usize + len > next as usize {
+                errors.push(LinkError::Overlap {
+                    first: first.clone(),
+                    second: second.clone(),
+                    address: next,
+                });
+            }
+        }
+        match entry {
+            Some(entry) if errors.is_empty() => Ok(Tape {
+                entry,
+                blocks,
+                relocations: None,
+                checksums: false,
+            }),
+            Some(_) => Err(errors),
+            None => {
+                errors.push(LinkError::NoEntry);
+                Err(errors)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    fn module(size: u16, origin: u16, data: Vec<u8>) -> Object {
+        let mut object = Object::new();
+        object.size = size;
+        object.entry = Some(0);
+        object.blocks.push(Block { origin, data });
+        object
+    }
+
+    #[test]
+    fn modules_are_placed_one_after_another() {
+        let mut linker = Linker::new();
+        linker.add("a.o".to_string(), module(2, 0, vec![0xC0, 0x00]));
+        linker.add("b.o".to_string(), module(2, 0, vec![0xC0, 0x00]));
+        let tape = linker.link(0x100).unwrap();
+        let origins: Vec<u16> = tape.blocks.iter().map(|block| block.origin).collect();
+        assert_eq!(origins, vec![0x100, 0x102]);
+        assert_eq!(tape.entry, 0x100);
+    }
+
+    #[test]
+    fn blocks_past_their_module_overlap_the_next() {
+        let mut linker = Linker::new();
+        linker.add(
+            "a.o".to_string(),
+            module(2, 0, vec![0xC0, 0x00, 0xC0, 0x00]),
+        );
+        linker.add("b.o".to_string(), module(2, 0, vec![0xC0, 0x00]));
+        assert_eq!(
+            linker.link(0x100),
+            Err(vec![LinkError::Overlap {
+                first: "a.o".to_string(),
+                second: "b.o".to_string(),
+                address: 0x102,
+            }])
+        );
+    }
+
+    #[test]
+    fn addresses_past_memory_are_errors() {
+        let out_of_memory = LinkError::OutOfMemory {
+            module: "a.o".to_string(),
+            end: 0x10100,
+        };
+        let mut object = module(2, 0, vec![0xC0, 0x00]);
+        object.exports.insert("X".to_string(), 0xFFFF);
+        let mut linker = Linker::new();
+        linker.add("a.o".to_string(), object);
+        assert_eq!(linker.link(0x100), Err(vec![out_of_memory.clone()]));
+
+        let mut linker = Linker::new();
+        linker.add("a.o".to_string(), module(2, 0xFFFF, vec![0xC0]));
+        assert_eq!(linker.link(0x100), Err(vec![out_of_memory]));
+    }
+}
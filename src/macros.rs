@@ -0,0 +1,451 @@
+use crate::assembler::{is_identifier, split_words, Word};
+use crate::{AssembleError, Span, Symbols};
+use std::collections::HashMap;
+
+/// How deeply macros may call each other before the call is taken to be
+/// endless recursion.
+const MAX_DEPTH: usize = 16;
+
+/// A line of source, after includes and macros are expanded.
+#[derive(Debug, Clone)]
+pub(crate) struct Line {
+    pub text: String,
+    pub file: String,
+    /// Line number in `file`. Lines of an expansion keep the number of the
+    /// body line they came from.
+    pub number: usize,
+    /// How many bytes of `text` are assembled. Directives stay in for the
+    /// listing, but only the label of a macro call is assembled.
+    pub code_len: usize,
+    /// Name of the macro at the call site, if the line came from a macro.
+    pub expansion: Option<Span>,
+    /// Path in the `INCLUDE` that brought in `file`, if any.
+    pub include: Option<Span>,
+}
+
+impl Line {
+    pub fn new(text: String, file: String, number: usize, include: Option<Span>) -> Line {
+        Line {
+            code_len: text.len(),
+            text,
+            file,
+            number,
+            expansion: None,
+            include,
+        }
+    }
+
+    /// Span covering `word`, which must be taken from this line.
+    pub fn span(&self, word: &Word) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.number,
+            column: word.column,
+            len: word.text.chars().count(),
+            text: self.text.clone(),
+            expansion: self.expansion.clone().map(Box::new),
+            included_from: self.include.clone().map(Box::new),
+        }
+    }
+
+    /// The line kept in the listing without being assembled.
+    fn skipped(mut self) -> Line {
+        self.code_len = 0;
+        self
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    /// Each line between `MACRO` and `ENDM`.
+    body: Vec<Line>,
+    /// Labels defined in the body, renamed in every expansion.
+    locals: Vec<String>,
+}
+
+/// Replaces every call of a `MACRO ... ENDM` definition with its body.
+pub(crate) struct Expander<'a> {
+    symbols: &'a Symbols,
+    macros: HashMap<String, Macro>,
+    /// Number of expansions so far, used to name local labels.
+    expansions: usize,
+    pub lines: Vec<Line>,
+    pub errors: Vec<AssembleError>,
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(symbols: &'a Symbols) -> Expander<'a> {
+        Expander {
+            symbols,
+            macros: HashMap::new(),
+            expansions: 0,
+            lines: vec![],
+            errors: vec![],
+        }
+    }
+
+    /// Expands every line of `source`. Macros must be defined before use.
+    pub fn expand(&mut self, source: &[Line]) {
+        let mut idx = 0;
+        while idx < source.len() {
+            let line = &source[idx];
+            let words = split_words(&line.text[..line.code_len]);
+            match words.first().map(|word| word.text.as_str()) {
+                Some("MACRO") => {
+                    let end = source[idx + 1..]
+                        .iter()
+                        .position(|line| first_word(&line.text).as_deref() == Some("ENDM"));
+                    let last = match end {
+                        Some(offset) => idx + 1 + offset,
+                        None => {
+                            let span = line.span(&words[0]);
+                            let message = "`MACRO` has no matching `ENDM`".to_string();
+                            self.errors.push(AssembleError::new(message, Some(span)));
+                            source.len() - 1
+                        }
+                    };
+                    self.define(&source[idx..=last]);
+                    for line in source[idx..=last].iter() {
+                        self.lines.push(line.clone().skipped());
+                    }
+                    idx = last + 1;
+                }
+                Some("ENDM") => {
+                    let span = line.span(&words[0]);
+                    let message = "`ENDM` without a `MACRO`".to_string();
+                    self.errors.push(AssembleError::new(message, Some(span)));
+                    self.lines.push(line.clone().skipped());
+                    idx += 1;
+                }
+                _ => {
+                    self.line(line.clone(), 0);
+                    idx += 1;
+                }
+            }
+        }
+    }
+
+    /// Records the macro defined by `lines`, which start at `MACRO` and end
+    /// at `ENDM`.
+    fn define(&mut self, lines: &[Line]) {
+        let header = split_words(&lines[0].text);
+        let name = match header.get(1) {
+            Some(name) => name,
+            None => {
+                let span = lines[0].span(&header[0]);
+                let error = AssembleError::new("`MACRO` needs a name".to_string(), Some(span))
+                    .with_help("write `MACRO NAME arg1, arg2`".to_string());
+                self.errors.push(error);
+                return;
+            }
+        };
+        let problem = if self.symbols.opcode(&name.text).is_some() {
+            Some(format!(
+                "`{}` is an instruction and cannot be a macro name",
+                name.text
+            ))
+        } else if self.macros.contains_key(&name.text) {
+            Some(format!("macro `{}` is defined more than once", name.text))
+        } else {
+            None
+        };
+        if let Some(message) = problem {
+            let span = lines[0].span(name);
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return;
+        }
+        let params = match header.get(2) {
+            Some(first) => {
+                let last = &header[header.len() - 1];
+                split_arguments(&lines[0].text[first.start..last.end])
+            }
+            None => vec![],
+        };
+        if let Some(param) = params.iter().find(|param| !is_identifier(param)) {
+            let span = lines[0].span(&header[2]);
+            let message = format!("`{}` is not a valid parameter name", param);
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return;
+        }
+        // The body would be rewritten wherever the instruction is used
+        let reserved = |param: &str| {
+            self.symbols.opcode(param).is_some() || matches!(param, "MACRO" | "ENDM" | "INCLUDE")
+        };
+        if let Some(param) = params.iter().find(|param| reserved(param)) {
+            let span = lines[0].span(&header[2]);
+            let message = format!(
+                "`{}` is an instruction or directive and cannot be a parameter name",
+                param
+            );
+            self.errors.push(AssembleError::new(message, Some(span)));
+            return;
+        }
+        let body_end = lines.len().saturating_sub(1).max(1);
+        let mut locals = vec![];
+        for line in lines[1..body_end].iter() {
+            let words = split_words(&line.text);
+            if let Some(first) = words.first() {
+                if first.text == "MACRO" {
+                    let span = line.span(first);
+                    let message = "macros cannot be defined inside a macro".to_string();
+                    self.errors.push(AssembleError::new(message, Some(span)));
+                    return;
+                }
+                let is_label = self.symbols.opcode(&first.text).is_none()
+                    && !self.macros.contains_key(&first.text)
+                    && first.text != name.text
+                    && !params.contains(&first.text);
+                if is_label && !locals.contains(&first.text) {
+                    locals.push(first.text.clone());
+                }
+            }
+        }
+        let definition = Macro {
+            params,
+            body: lines[1..body_end].to_vec(),
+            locals,
+        };
+        self.macros.insert(name.text.clone(), definition);
+    }
+
+    /// Adds a line, expanding it if it calls a macro.
+    fn line(&mut self, line: Line, depth: usize) {
+        let words = split_words(&line.text[..line.code_len]);
+        let call = match words.as_slice() {
+            [first, ..] if self.macros.contains_key(&first.text) => 0,
+            [first, second, ..]
+                if self.symbols.opcode(&first.text).is_none()
+                    && self.macros.contains_key(&second.text) =>
+            {
+                1
+            }
+            _ => {
+                self.lines.push(line);
+                return;
+            }
+        };
+        // Only the label of a call is assembled, at the start of the expansion
+        let mut kept = line.clone();
+        kept.code_len = if call == 1 { words[0].end } else { 0 };
+        self.lines.push(kept);
+        self.call(&line, &words[call..], depth);
+    }
+
+    fn call(&mut self, line: &Line, words: &[Word], depth: usize) {
+        let site = line.span(&words[0]);
+        let name = &words[0].text;
+        if depth >= MAX_DEPTH {
+            let message = format!("macro `{}` is nested too deeply", name);
+            let error = AssembleError::new(message, Some(site))
+                .with_help("a macro may not call itself".to_string());
+            self.errors.push(error);
+            return;
+        }
+        let definition = &self.macros[name];
+        let args = match words.get(1) {
+            Some(first) => split_arguments(&line.text[first.start..words[words.len() - 1].end]),
+            None => vec![],
+        };
+        if args.len() != definition.params.len() {
+            let message = format!(
+                "macro `{}` takes {} argument(s) but {} were given",
+                name,
+                definition.params.len(),
+                args.len()
+            );
+            let help = match definition.params.is_empty() {
+                true => format!("`{}` has no parameters", name),
+                false => format!("parameters are {}", definition.params.join(", ")),
+            };
+            let error = AssembleError::new(message, Some(site)).with_help(help);
+            self.errors.push(error);
+            return;
+        }
+        self.expansions += 1;
+        let mut replacements = HashMap::new();
+        for local in definition.locals.iter() {
+            replacements.insert(local.clone(), format!("{}.{}", local, self.expansions));
+        }
+        for (param, arg) in definition.params.iter().zip(args) {
+            replacements.insert(param.clone(), arg);
+        }
+        for body_line in definition.body.clone() {
+            let text = substitute(&body_line.text, &replacements);
+            let expanded = Line {
+                code_len: text.len(),
+                text,
+                expansion: Some(site.clone()),
+                ..body_line
+            };
+            self.line(expanded, depth + 1);
+        }
+    }
+}
+
+fn first_word(line: &str) -> Option<String> {
+    split_words(line).into_iter().next().map(|word| word.text)
+}
+
+/// Splits on commas outside of parentheses and string literals.
+fn split_arguments(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => escaped = false,
+            }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(current.trim().to_string());
+                    current.clear();
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !args.is_empty() {
+        args.push(current.trim().to_string());
+    }
+    args
+}
+
+/// Replaces whole names in `text`, leaving strings and comments alone.
+fn substitute(text: &str, replacements: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut chars = text.char_indices().peekable();
+    let mut in_string = false;
+    let mut escaped = false;
+    while let Some((idx, c)) = chars.next() {
+        if in_string {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => escaped = false,
+            }
+            out.push(c);
+            continue;
+        }
+        if c == ';' {
+            out.push_str(&text[idx..]);
+            break;
+        }
+        if !c.is_ascii_alphanumeric() && c != '_' {
+            in_string = c == '"';
+            out.push(c);
+            continue;
+        }
+        let mut end = idx + c.len_utf8();
+        while let Some((next, c)) = chars.peek() {
+            if !c.is_ascii_alphanumeric() && *c != '_' && *c != '.' {
+                break;
+            }
+            end = next + c.len_utf8();
+            chars.next();
+        }
+        let name = &text[idx..end];
+        // Numbers such as 0x1F and /1F are copied as they are, but a `/`
+        // right after a value divides by the name
+        let mut before = text[..idx].chars().rev();
+        let ends_value = |c: char| c.is_ascii_alphanumeric() || "_.)$\"".contains(c);
+        let hex = before.next() == Some('/') && !before.next().is_some_and(ends_value);
+        match replacements.get(name) {
+            Some(replacement) if !c.is_ascii_digit() && !hex => out.push_str(replacement),
+            _ => out.push_str(name),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand<'a>(symbols: &'a Symbols, source: &str) -> Expander<'a> {
+        let lines: Vec<Line> = source
+            .lines()
+            .enumerate()
+            .map(|(idx, text)| Line::new(text.to_string(), "test.asm".to_string(), idx + 1, None))
+            .collect();
+        let mut expander = Expander::new(symbols);
+        expander.expand(&lines);
+        expander
+    }
+
+    /// Assembled text of every line, skipping those left only for the listing.
+    fn code(expander: &Expander) -> Vec<String> {
+        expander
+            .lines
+            .iter()
+            .map(|line| line.text[..line.code_len].trim().to_string())
+            .filter(|text| !text.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn hex_literals_are_not_parameters() {
+        let replacements: HashMap<String, String> = [("A", "COUNT"), ("FF", "LIMIT")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(substitute("LV /A", &replacements), "LV /A");
+        assert_eq!(substitute("LV A + /FF", &replacements), "LV COUNT + /FF");
+        assert_eq!(substitute("LV (/A)//FF", &replacements), "LV (/A)//FF");
+        assert_eq!(substitute("LV 10/A", &replacements), "LV 10/COUNT");
+        assert_eq!(substitute("LV (A)/FF", &replacements), "LV (COUNT)/LIMIT");
+        assert_eq!(substitute("LV 0xA ; A", &replacements), "LV 0xA ; A");
+    }
+
+    #[test]
+    fn locals_are_renamed_but_not_hex_literals() {
+        let symbols = Symbols::new();
+        let expander = expand(
+            &symbols,
+            "MACRO WAIT\nA LV /A\n  JP A\nENDM\n  WAIT\n  WAIT\n",
+        );
+        assert_eq!(expander.errors, vec![]);
+        assert_eq!(
+            code(&expander),
+            vec!["A.1 LV /A", "JP A.1", "A.2 LV /A", "JP A.2"]
+        );
+    }
+
+    #[test]
+    fn parameters_are_replaced_by_arguments() {
+        let symbols = Symbols::new();
+        let expander = expand(
+            &symbols,
+            "MACRO PUT VALUE, FF\n  LV VALUE + /FF\n  PD FF\nENDM\n  PUT 1, /100\n",
+        );
+        assert_eq!(expander.errors, vec![]);
+        assert_eq!(code(&expander), vec!["LV 1 + /FF", "PD /100"]);
+    }
+
+    #[test]
+    fn parameters_cannot_be_named_after_instructions() {
+        for param in ["K", "LV", "EQU", "ENDM"] {
+            let source = format!("MACRO DATA {}\n  K {}\nENDM\n", param, param);
+            let symbols = Symbols::new();
+            let errors = expand(&symbols, &source).errors;
+            assert_eq!(errors.len(), 1, "{}", param);
+            assert_eq!(
+                errors[0].message,
+                format!(
+                    "`{}` is an instruction or directive and cannot be a parameter name",
+                    param
+                )
+            );
+        }
+    }
+}
@@ -1,6 +1,11 @@
 use clap::{App, Arg, SubCommand};
-use sisprog::{Assembler, Config, CPU};
+use sisprog::{
+    Assembler, AssemblerConfig, Config, Debugger, Disassembler, ImageFormat, Inspection, Linker,
+    MvnImage, ProfileFormat, ProfileOutput, CPU,
+};
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn main() {
     let matches = App::new("PCS3216")
@@ -9,6 +14,24 @@ fn main() {
         .about("Simple VM that assembles and runs arbitrary code")
         .subcommand(
             SubCommand::with_name("cpu")
+                .after_help(
+                    "EXIT CODES:\n    0   machine halted, or exited with OS 0 and status 0\n    \
+                     1   unable to start the machine\n    \
+                     10  division by zero\n    11  accumulator overflow, with --strict\n    \
+                     12  instruction fetch past end of memory\n    \
+                     13  data access past end of memory\n    14  unimplemented OS call\n    \
+                     15  read after end of input\n    16  unable to write output\n    \
+                     17  unable to read input\n    18  no device mapped to the number\n    \
+                     19  limit reached (--max-instructions, --max-output-bytes, --timeout)\n    \
+                     20  negative exit status given to OS 0\n    \
+                     65-191  program exited with OS 0, status 1-127, as 64 + status\n\n\
+                     SUPERVISOR CALLS:\n    OS 0  exit with AC, 0 to 127, as the status\n    \
+                     OS 1  write AC to device 0 in decimal\n    \
+                     OS 2  read a decimal number from device 0 into AC\n    \
+                     OS 3  write the zero-terminated string at the address in the next word\n    \
+                     OS 4  store the instruction count, 4 bytes, at the address in the next word\n    \
+                     OS 5  break into the debugger",
+                )
                 .arg(
                     Arg::with_name("OUTPUT")
                         .value_name("OUTPUT FILE")
@@ -27,9 +50,90 @@ fn main() {
                     Arg::with_name("LOADER")
                         .value_name("LOADER FILE")
                         .short("L")
-                        .required(true)
+                        .required_unless_one(&["LOAD_ADDRESS", "FORMAT"])
                         .help("Loader to be used. Must be in a binary format"),
                 )
+                .arg(
+                    Arg::with_name("LOAD_ADDRESS")
+                        .value_name("ADDRESS")
+                        .long("load-address")
+                        .conflicts_with("LOADER")
+                        .help(
+                            "Loads the tape without a loader, moved up by ADDRESS. Only \
+                             relocatable tapes can be moved from where they were assembled",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .value_name("FORMAT")
+                        .long("format")
+                        .possible_values(&ImageFormat::NAMES)
+                        .conflicts_with_all(&["LOADER", "LOAD_ADDRESS"])
+                        .help(
+                            "Loads INPUT straight into memory as a program in FORMAT, without a \
+                             loader. MVN text starts at its first address",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("DEVICE")
+                        .value_name("N=DEVICE")
+                        .long("device")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "Maps device number N, the operand of GD and PD, to stdin, stdout, \
+                             null, buffer or file:PATH. Device 0 reads the rest of INPUT and \
+                             writes OUTPUT unless mapped",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("STRICT")
+                        .long("strict")
+                        .help("Faults on signed overflow in + - * / instead of wrapping around"),
+                )
+                .arg(
+                    Arg::with_name("MAX_INSTRUCTIONS")
+                        .value_name("COUNT")
+                        .long("max-instructions")
+                        .help("Stops the machine after executing COUNT instructions"),
+                )
+                .arg(
+                    Arg::with_name("MAX_OUTPUT_BYTES")
+                        .value_name("COUNT")
+                        .long("max-output-bytes")
+                        .help("Stops the machine before it writes more than COUNT bytes"),
+                )
+                .arg(
+                    Arg::with_name("TIMEOUT")
+                        .value_name("SECONDS")
+                        .long("timeout")
+                        .help("Stops the machine after running for SECONDS, which may be fractional"),
+                )
+                .arg(
+                    Arg::with_name("PROFILE")
+                        .value_name("PROFILE FILE")
+                        .long("profile")
+                        .help(
+                            "Counts executions per address, opcode and SC subroutine and writes \
+                             them to PROFILE FILE when the machine stops",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("PROFILE_FORMAT")
+                        .value_name("FORMAT")
+                        .long("profile-format")
+                        .possible_values(&ProfileFormat::NAMES)
+                        .requires("PROFILE")
+                        .help("Sorted text tables, the default, or folded stacks for flame graph tools"),
+                )
+                .arg(
+                    Arg::with_name("SYMBOLS")
+                        .value_name("SYMBOLS FILE")
+                        .short("s")
+                        .long("symbols")
+                        .requires("PROFILE")
+                        .help("Symbol file written by the assembler, to group the profile by label"),
+                )
                 .arg(
                     Arg::with_name("v")
                         .short("v")
@@ -37,6 +141,43 @@ fn main() {
                         .help("Sets the level of verbosity"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs object code under an interactive debugger")
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT FILE")
+                        .required(true)
+                        .help("Location to save output of program")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Absolute object code to be run")
+                        .value_name("INPUT FILE")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("LOADER")
+                        .value_name("LOADER FILE")
+                        .short("L")
+                        .required(true)
+                        .help("Loader to be used. Must be in a binary format"),
+                )
+                .arg(
+                    Arg::with_name("SYMBOLS")
+                        .value_name("SYMBOLS FILE")
+                        .short("s")
+                        .long("symbols")
+                        .help("Symbol file written by the assembler"),
+                )
+                .arg(
+                    Arg::with_name("STRICT")
+                        .long("strict")
+                        .help("Faults on signed overflow in + - * / instead of wrapping around"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("assembler")
                 .arg(
@@ -52,6 +193,142 @@ fn main() {
                         .value_name("INPUT FILE")
                         .required(true)
                         .index(2),
+                )
+                .arg(
+                    Arg::with_name("SYMBOLS")
+                        .value_name("SYMBOLS FILE")
+                        .short("s")
+                        .long("symbols")
+                        .help("Location to save the symbol table and source map"),
+                )
+                .arg(
+                    Arg::with_name("LISTING")
+                        .value_name("LISTING FILE")
+                        .short("l")
+                        .long("listing")
+                        .help("Location to save a human readable listing"),
+                )
+                .arg(
+                    Arg::with_name("DEFINE")
+                        .value_name("NAME=VALUE")
+                        .short("D")
+                        .long("define")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Defines a constant as if by EQU. VALUE defaults to 1"),
+                )
+                .arg(
+                    Arg::with_name("OBJECT")
+                        .short("c")
+                        .long("object")
+                        .help("Writes a relocatable object for `link` instead of a tape"),
+                )
+                .arg(
+                    Arg::with_name("RELOCATABLE")
+                        .short("r")
+                        .long("relocatable")
+                        .conflicts_with("OBJECT")
+                        .help("Writes a tape for `cpu --load-address`, which can run anywhere"),
+                )
+                .arg(
+                    Arg::with_name("CHECKSUM")
+                        .long("checksum")
+                        .help("Ends each tape record with a checksum, for `cpu --load-address`"),
+                )
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .value_name("FORMAT")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&ImageFormat::NAMES)
+                        .conflicts_with("OBJECT")
+                        .help("Format of the program written to OUTPUT. Defaults to tape"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("link")
+                .about("Combines relocatable objects into a tape")
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT FILE")
+                        .required(true)
+                        .help("Location to save the tape")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .value_name("OBJECT FILE")
+                        .required(true)
+                        .multiple(true)
+                        .help("Objects to place in memory, in order")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("ORIGIN")
+                        .value_name("ADDRESS")
+                        .long("origin")
+                        .help("Where the first object is placed. Defaults to /100"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Prints the assembly of a tape produced by the assembler")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Tape to be disassembled")
+                        .value_name("INPUT FILE")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("SYMBOLS")
+                        .value_name("SYMBOLS FILE")
+                        .short("s")
+                        .long("symbols")
+                        .help("Symbol file written by the assembler"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts between tapes and MVN simulator text")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Tape or MVN text to be converted")
+                        .value_name("INPUT FILE")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .value_name("OUTPUT FILE")
+                        .required(true)
+                        .help("Location to save the converted program")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .value_name("FORMAT")
+                        .long("to")
+                        .required(true)
+                        .possible_values(&["mvn", "tape"])
+                        .help("Format to convert to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Checks that a tape will load, decoding it the way the loader does")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Tape to be inspected")
+                        .value_name("INPUT FILE")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("LOADER")
+                        .value_name("LOADER FILE")
+                        .short("L")
+                        .help("Loader whose memory the tape must not load over"),
                 ),
         )
         .get_matches();
@@ -61,19 +338,116 @@ fn main() {
     if let Some(matches) = matches.subcommand_matches("cpu") {
         match matches.occurrences_of("v") {
             0 => (),
-            1 => env::set_var(key, "info".to_string()),
-            2 => env::set_var(key, "debug".to_string()),
-            _ => env::set_var(key, "trace".to_string()),
+            1 => env::set_var(key, "info"),
+            2 => env::set_var(key, "debug"),
+            _ => env::set_var(key, "trace"),
         }
         pretty_env_logger::init();
         let inp = matches.value_of("INPUT").unwrap().to_string();
         let out = matches.value_of("OUTPUT").unwrap().to_string();
-        let loader = matches.value_of("LOADER").unwrap().to_string();
-        let conf = Config::new(inp, out, loader, false);
+        let mut conf = match matches.value_of("LOAD_ADDRESS") {
+            Some(address) => Config::relocated(inp, out, address).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }),
+            None if matches.is_present("FORMAT") => {
+                let format = ImageFormat::from_name(matches.value_of("FORMAT").unwrap()).unwrap();
+                Config::image(inp, out, format).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                })
+            }
+            None => {
+                let loader = matches.value_of("LOADER").unwrap().to_string();
+                Config::new(inp, out, loader).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                })
+            }
+        };
+        conf.strict = matches.is_present("STRICT");
+        conf.max_instructions = parse_limit(matches.value_of("MAX_INSTRUCTIONS"));
+        conf.max_output_bytes = parse_limit(matches.value_of("MAX_OUTPUT_BYTES"));
+        conf.timeout = parse_limit::<f64>(matches.value_of("TIMEOUT")).map(|seconds| {
+            Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| {
+                eprintln!("Invalid timeout {}", seconds);
+                std::process::exit(1);
+            })
+        });
+        conf.profile = matches.value_of("PROFILE").map(|path| ProfileOutput {
+            path: path.to_string(),
+            format: matches
+                .value_of("PROFILE_FORMAT")
+                .and_then(ProfileFormat::from_name)
+                .unwrap_or(ProfileFormat::Table),
+            symbols: matches.value_of("SYMBOLS").map(|s| s.to_string()),
+        });
+        for mapping in matches.values_of("DEVICE").into_iter().flatten() {
+            conf.attach(mapping).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+        }
         CPU::run(conf);
+    } else if let Some(matches) = matches.subcommand_matches("debug") {
+        pretty_env_logger::init();
+        let inp = matches.value_of("INPUT").unwrap().to_string();
+        let out = matches.value_of("OUTPUT").unwrap().to_string();
+        let loader = matches.value_of("LOADER").unwrap().to_string();
+        let symbols = matches.value_of("SYMBOLS").map(|s| s.to_string());
+        let mut conf = Config::new(inp, out, loader).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        conf.strict = matches.is_present("STRICT");
+        Debugger::run(conf, symbols);
     } else if let Some(matches) = matches.subcommand_matches("assembler") {
         let inp = matches.value_of("INPUT").unwrap().to_string();
         let out = matches.value_of("OUTPUT").unwrap().to_string();
-        Assembler::run(inp, out);
+        let mut conf = AssemblerConfig::new(inp, out);
+        conf.symbols = matches.value_of("SYMBOLS").map(|s| s.to_string());
+        conf.listing = matches.value_of("LISTING").map(|s| s.to_string());
+        if let Some(defines) = matches.values_of("DEFINE") {
+            conf.defines = defines
+                .map(|define| match define.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (define.to_string(), "1".to_string()),
+                })
+                .collect();
+        }
+        conf.object = matches.is_present("OBJECT");
+        conf.relocatable = matches.is_present("RELOCATABLE");
+        conf.checksums = matches.is_present("CHECKSUM");
+        if let Some(format) = matches.value_of("FORMAT") {
+            conf.format = ImageFormat::from_name(format).unwrap();
+        }
+        Assembler::run(conf);
+    } else if let Some(matches) = matches.subcommand_matches("link") {
+        let out = matches.value_of("OUTPUT").unwrap().to_string();
+        let inputs = matches.values_of("INPUT").unwrap().map(|s| s.to_string());
+        let origin = matches.value_of("ORIGIN").map(|s| s.to_string());
+        Linker::run(out, inputs.collect(), origin);
+    } else if let Some(matches) = matches.subcommand_matches("disasm") {
+        let inp = matches.value_of("INPUT").unwrap().to_string();
+        let symbols = matches.value_of("SYMBOLS").map(|s| s.to_string());
+        Disassembler::run(inp, symbols);
+    } else if let Some(matches) = matches.subcommand_matches("convert") {
+        let inp = matches.value_of("INPUT").unwrap().to_string();
+        let out = matches.value_of("OUTPUT").unwrap().to_string();
+        MvnImage::convert(inp, out, matches.value_of("TO").unwrap());
+    } else if let Some(matches) = matches.subcommand_matches("inspect") {
+        let inp = matches.value_of("INPUT").unwrap().to_string();
+        let loader = matches.value_of("LOADER").map(|s| s.to_string());
+        Inspection::run(inp, loader);
     }
 }
+
+/// Parses the value of a limit option, exiting if it is not a number.
+fn parse_limit<T: FromStr>(value: Option<&str>) -> Option<T> {
+    value.map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid limit {}", value);
+            std::process::exit(1);
+        })
+    })
+}
@@ -0,0 +1,215 @@
+use crate::tape::from_memory;
+use crate::Tape;
+use std::fs;
+
+/// A program in the text format of the course's MVN simulator: one word per
+/// line, written as a four digit hex address and a four digit hex value.
+///
+/// ```text
+/// 0F00 8F26
+/// 0100 0C01
+/// 0F02 9F10
+/// ```
+///
+/// The format has no entry point, so the first line is taken to be where
+/// the program starts. Text after a `;` is ignored.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MvnImage {
+    /// Address and value of each word, in the order they are written.
+    pub words: Vec<(u16, u16)>,
+}
+
+impl MvnImage {
+    /// Converts between a tape and MVN text. `to` is `mvn` or `tape`.
+    pub fn convert(input: String, output: String, to: &str) {
+        let result = match to {
+            "mvn" => fs::read(&input)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| Tape::parse(&bytes).map_err(|err| err.to_string()))
+                .map(|(tape, _)| MvnImage::from_tape(&tape).to_text().into_bytes()),
+            _ => MvnImage::load(&input)
+                .and_then(|image| image.to_tape().to_bytes().map_err(|err| err.to_string())),
+        };
+        let bytes = result.unwrap_or_else(|err| {
+            eprintln!("Unable to convert {}: {}", input, err);
+            std::process::exit(1);
+        });
+        if let Err(err) = fs::write(&output, bytes) {
+            eprintln!("Unable to write {}: {}", output, err);
+            std::process::exit(1);
+        }
+    }
+
+    pub fn load(path: &str) -> Result<MvnImage, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        MvnImage::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<MvnImage, String> {
+        let mut words = vec![];
+        for (idx, line) in text.lines().enumerate() {
+            let code = line.split(';').next().unwrap_or("");
+            let fields: Vec<&str> = code.split_whitespace().collect();
+            let (address, value) = match fields.as_slice() {
+                [] => continue,
+                [address, value] => (
+                    u16::from_str_radix(address, 16),
+                    u16::from_str_radix(value, 16),
+                ),
+                _ => return Err(format!("Malformed line {}: {}", idx + 1, line)),
+            };
+            let (address, value) = match (address, value) {
+                (Ok(address), Ok(value)) => (address, value),
+                _ => return Err(format!("Malformed line {}: {}", idx + 1, line)),
+            };
+            if address >= 0x0FFF {
+                return Err(format!(
+                    "Line {} writes past the end of memory: {}",
+                    idx + 1,
+                    line
+                ));
+            }
+            words.push((address, value));
+        }
+        Ok(MvnImage { words })
+    }
+
+    /// Writes every byte loaded by `tape` as words, the word at the entry
+    /// point first. A byte without a neighbour to pair with is paired with 0.
+    pub fn from_tape(tape: &Tape) -> MvnImage {
+        let mut memory = vec![None; 4096];
+        for block in tape.blocks.iter() {
+            for (idx, byte) in block.data.iter().enumerate() {
+                if let Some(cell) = memory.get_mut(block.origin as usize + idx) {
+                    *cell = Some(*byte);
+                }
+            }
+        }
+        let word = |address: u16| {
+            let byte = |address: u16| memory[address as usize].unwrap_or(0);
+            (
+                address,
+                u16::from_be_bytes([byte(address), byte(address + 1)]),
+            )
+        };
+        let entry = tape.entry_point().min(0x0FFE);
+        let mut words = vec![word(entry)];
+        let mut addresses = vec![];
+        for block in tape.blocks.iter() {
+            for offset in (0..block.data.len() as u16).step_by(2) {
+                // The last word of memory can only start at /FFE
+                addresses.push((block.origin + offset).min(0x0FFE));
+            }
+        }
+        addresses.sort_unstable();
+        addresses.dedup();
+        words.extend(
+            addresses
+                .into_iter()
+                .filter(|address| *address != entry)
+                .map(word),
+        );
+        MvnImage { words }
+    }
+
+    /// Lays the words out in memory, with a block for each run of loaded
+    /// bytes and the first word as the entry point.
+    pub fn to_tape(&self) -> Tape {
+        let mut memory = vec![None; 4096];
+        for (address, value) in self.words.iter() {
+            let [msb, lsb] = value.to_be_bytes();
+            memory[*address as usize] = Some(msb);
+            memory[*address as usize + 1] = Some(lsb);
+        }
+        from_memory(&memory, self.entry().unwrap_or(0))
+    }
+
+    /// Where the program starts, taken from the first line.
+    pub fn entry(&self) -> Option<u16> {
+        self.words.first().map(|(address, _)| *address)
+    }
+
+    pub fn to_text(&self) -> String {
+        self.words
+            .iter()
+            .map(|(address, value)| format!("{:04X} {:04X}\n", address, value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    #[test]
+    fn tape_round_trips_through_text() {
+        let tape = Tape::plain(
+            0x100,
+            vec![
+                Block {
+                    origin: 0x100,
+                    data: vec![0x01, 0x20, 0xC0, 0x00],
+                },
+                Block {
+                    origin: 0x200,
+                    data: b"Hi".to_vec(),
+                },
+            ],
+        );
+        let text = MvnImage::from_tape(&tape).to_text();
+        assert_eq!(text, "0100 0120\n0102 C000\n0200 4869\n");
+        assert_eq!(MvnImage::parse(&text).unwrap().to_tape(), tape);
+    }
+
+    #[test]
+    fn entry_point_is_written_first() {
+        let tape = Tape::plain(
+            0x102,
+            vec![Block {
+                origin: 0x100,
+                data: vec![0x00, 0x00, 0x01, 0x20],
+            }],
+        );
+        let image = MvnImage::from_tape(&tape);
+        assert_eq!(image.words, vec![(0x102, 0x0120), (0x100, 0x0000)]);
+        assert_eq!(image.entry(), Some(0x102));
+        assert_eq!(image.to_tape(), tape);
+    }
+
+    #[test]
+    fn odd_byte_is_paired_with_zero() {
+        let tape = Tape::plain(
+            0x200,
+            vec![Block {
+                origin: 0x200,
+                data: vec![0x41],
+            }],
+        );
+        let image = MvnImage::from_tape(&tape);
+        assert_eq!(image.words, vec![(0x200, 0x4100)]);
+        assert_eq!(image.to_tape().blocks[0].data, vec![0x41, 0x00]);
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let image = MvnImage::parse("; hello\n\n0100 0120 ; load\n0102 c000\n").unwrap();
+        assert_eq!(image.words, vec![(0x100, 0x0120), (0x102, 0xC000)]);
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert_eq!(
+            MvnImage::parse("0100 0120\n0102\n"),
+            Err("Malformed line 2: 0102".to_string())
+        );
+        assert_eq!(
+            MvnImage::parse("01G0 0120\n"),
+            Err("Malformed line 1: 01G0 0120".to_string())
+        );
+        assert_eq!(
+            MvnImage::parse("0FFF 0120\n"),
+            Err("Line 1 writes past the end of memory: 0FFF 0120".to_string())
+        );
+    }
+}
@@ -0,0 +1,32 @@
+/// Parses `/1F0` as hex, `0x1F0` as hex and anything else as decimal.
+pub(crate) fn parse_number(word: &str) -> Option<u16> {
+    if let Some(hex) = word.strip_prefix('/') {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Some(hex) = word.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    word.parse::<u16>().ok()
+}
+
+/// Parses an address as written in object and symbol files, always in hex
+/// after a `/`.
+pub(crate) fn parse_address(word: &str) -> Option<u16> {
+    u16::from_str_radix(word.strip_prefix('/')?, 16).ok()
+}
+
+/// Writes each byte as two hex digits.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Reads bytes written as pairs of hex digits.
+pub(crate) fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(text.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,196 @@
+use crate::number::{hex, parse_address, unhex};
+use crate::Block;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+/// A module assembled at relative addresses, to be placed by the linker.
+///
+/// Addresses are relative to the start of the module. The file is text, one
+/// record per line:
+///
+/// ```text
+/// ; sisprog object
+/// SIZE   /012
+/// ENTRY  /000
+/// EXPORT PRINT /004
+/// IMPORT NEWLINE
+/// BLOCK  /000 8004E1000002
+/// RELOC  /000
+/// EXTERN /004 NEWLINE
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Object {
+    /// Bytes of memory the module spans, including reserved space.
+    pub size: u16,
+    pub entry: Option<u16>,
+    pub exports: BTreeMap<String, u16>,
+    pub imports: Vec<String>,
+    pub blocks: Vec<Block>,
+    /// Instructions whose operand is an address within the module.
+    pub relocations: Vec<u16>,
+    /// Instructions whose operand is an offset from an imported symbol.
+    pub externals: Vec<(u16, String)>,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    pub fn load(path: &str) -> Result<Object, String> {
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut object = Object::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let malformed = || format!("Malformed line {}: {}", idx + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                [comment, ..] if comment.starts_with(';') => (),
+                ["SIZE", size] => object.size = parse_address(size).ok_or_else(malformed)?,
+                ["ENTRY", entry] => {
+                    object.entry = Some(parse_address(entry).ok_or_else(malformed)?);
+                }
+                ["EXPORT", name, address] => {
+                    let address = parse_address(address).ok_or_else(malformed)?;
+                    object.exports.insert(name.to_string(), address);
+                }
+                ["IMPORT", name] => object.imports.push(name.to_string()),
+                ["BLOCK", origin, data] => {
+                    let origin = parse_address(origin).ok_or_else(malformed)?;
+                    let data = unhex(data).ok_or_else(malformed)?;
+                    object.blocks.push(Block { origin, data });
+                }
+                ["RELOC", address] => {
+                    let address = parse_address(address).ok_or_else(malformed)?;
+                    object.relocations.push(address);
+                }
+                ["EXTERN", address, name] => {
+                    let address = parse_address(address).ok_or_else(malformed)?;
+                    object.externals.push((address, name.to_string()));
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        object.check()?;
+        Ok(object)
+    }
+
+    /// Checks that every block, export and the entry point lie within the
+    /// module, and that the module fits in memory.
+    fn check(&self) -> Result<(), String> {
+        if self.size > 0x1000 {
+            return Err(format!("SIZE /{:03X} is larger than memory", self.size));
+        }
+        for block in self.blocks.iter() {
+            let end = block.origin as usize + block.data.len();
+            if end > self.size as usize {
+                return Err(format!(
+                    "BLOCK /{:03X} ends at /{:03X}, past SIZE /{:03X}",
+                    block.origin, end, self.size
+                ));
+            }
+        }
+        for (name, address) in self.exports.iter() {
+            if *address >= self.size {
+                return Err(format!(
+                    "EXPORT {} /{:03X} is outside SIZE /{:03X}",
+                    name, address, self.size
+                ));
+            }
+        }
+        match self.entry {
+            Some(entry) if entry >= self.size => Err(format!(
+                "ENTRY /{:03X} is outside SIZE /{:03X}",
+                entry, self.size
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut contents = String::from("; sisprog object\n");
+        contents.push_str(&format!("SIZE   /{:03X}\n", self.size));
+        if let Some(entry) = self.entry {
+            contents.push_str(&format!("ENTRY  /{:03X}\n", entry));
+        }
+        for (name, address) in self.exports.iter() {
+            contents.push_str(&format!("EXPORT {} /{:03X}\n", name, address));
+        }
+        for name in self.imports.iter() {
+            contents.push_str(&format!("IMPORT {}\n", name));
+        }
+        for block in self.blocks.iter() {
+            contents.push_str(&format!(
+                "BLOCK  /{:03X} {}\n",
+                block.origin,
+                hex(&block.data)
+            ));
+        }
+        for address in self.relocations.iter() {
+            contents.push_str(&format!("RELOC  /{:03X}\n", address));
+        }
+        for (address, name) in self.externals.iter() {
+            contents.push_str(&format!("EXTERN /{:03X} {}\n", address, name));
+        }
+        fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> Object {
+        let mut object = Object::new();
+        object.size = 4;
+        object.entry = Some(0);
+        object.blocks.push(Block {
+            origin: 0,
+            data: vec![0xC0, 0x00, 0xC0, 0x00],
+        });
+        object.exports.insert("START".to_string(), 0);
+        object
+    }
+
+    #[test]
+    fn everything_within_the_module_is_accepted() {
+        assert_eq!(object().check(), Ok(()));
+    }
+
+    #[test]
+    fn blocks_past_size_are_rejected() {
+        let mut object = object();
+        object.size = 2;
+        assert_eq!(
+            object.check(),
+            Err("BLOCK /000 ends at /004, past SIZE /002".to_string())
+        );
+    }
+
+    #[test]
+    fn exports_and_entry_past_size_are_rejected() {
+        let mut bad = object();
+        bad.exports.insert("X".to_string(), 0xFFFF);
+        assert_eq!(
+            bad.check(),
+            Err("EXPORT X /FFFF is outside SIZE /004".to_string())
+        );
+        let mut bad = object();
+        bad.entry = Some(4);
+        assert_eq!(
+            bad.check(),
+            Err("ENTRY /004 is outside SIZE /004".to_string())
+        );
+    }
+
+    #[test]
+    fn modules_larger_than_memory_are_rejected() {
+        let mut object = object();
+        object.size = 0x1001;
+        assert_eq!(
+            object.check(),
+            Err("SIZE /1001 is larger than memory".to_string())
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{FaultSite, Mnemonics, SymbolFile};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+/// How a profile is written out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    /// Tables sorted by count, for people.
+    Table,
+    /// One line per call stack with its instruction count, for flame graph
+    /// tools such as `flamegraph.pl` and `inferno`.
+    Folded,
+}
+
+impl ProfileFormat {
+    /// Names of the formats, as given on the command line.
+    pub const NAMES: [&'static str; 2] = ["table", "folded"];
+
+    pub fn from_name(name: &str) -> Option<ProfileFormat> {
+        match name {
+            "table" => Some(ProfileFormat::Table),
+            "folded" => Some(ProfileFormat::Folded),
+            _ => None,
+        }
+    }
+}
+
+/// Where and how `CPU::run` writes the profile once the machine stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileOutput {
+    pub path: String,
+    pub format: ProfileFormat,
+    /// Symbol file from the assembler, to name addresses by label.
+    pub symbols: Option<String>,
+}
+
+impl ProfileOutput {
+    pub fn write(&self, profile: &Profile) -> Result<(), String> {
+        let symbols = match self.symbols.as_ref() {
+            Some(path) => SymbolFile::load(path)?,
+            None => SymbolFile::new(),
+        };
+        let text = match self.format {
+            ProfileFormat::Table => profile.table(&symbols),
+            ProfileFormat::Folded => profile.folded(&symbols),
+        };
+        fs::write(&self.path, text).map_err(|err| err.to_string())
+    }
+}
+
+/// Calls made to a subroutine and the instructions executed in it.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Subroutine {
+    pub calls: u64,
+    /// Instructions executed from the call to the return, including those in
+    /// the subroutines it called. Recursive calls are only counted once.
+    pub inclusive: u64,
+    /// Instructions executed in the subroutine itself.
+    pub exclusive: u64,
+}
+
+/// A subroutine being run.
+#[derive(Debug, Clone)]
+struct Frame {
+    address: u16,
+    /// Instructions executed before it was called.
+    entered: u64,
+}
+
+/// Counts of the instructions a CPU executed, by where they were and which
+/// subroutines were being run.
+///
+/// A subroutine is known by the operand of the `SC` that calls it, where
+/// its return address is kept, and ends at the next `RS`. The `SC` counts
+/// toward the caller and the `RS` toward the subroutine.
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    /// Executions of the instruction at each address.
+    pub addresses: BTreeMap<u16, u64>,
+    /// Executions of each opcode.
+    pub opcodes: [u64; 16],
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    /// Instructions executed under each chain of subroutines, outermost
+    /// first.
+    pub stacks: HashMap<Vec<u16>, u64>,
+    /// Instructions executed in all.
+    pub total: u64,
+    frames: Vec<Frame>,
+    /// Addresses of `frames`, kept apart to look up `stacks` with.
+    stack: Vec<u16>,
+}
+
+impl Profile {
+    /// Counts the instruction just executed.
+    pub fn record(&mut self, site: FaultSite) {
+        self.total += 1;
+        *self.addresses.entry(site.pc).or_default() += 1;
+        self.opcodes[site.opcode as usize] += 1;
+        match self.stacks.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+        if let Some(address) = self.stack.last() {
+            self.subroutines.entry(*address).or_default().exclusive += 1;
+        }
+        match site.opcode {
+            // SC
+            10 => {
+                self.subroutines.entry(site.operand).or_default().calls += 1;
+                self.frames.push(Frame {
+                    address: site.operand,
+                    entered: self.total,
+                });
+                self.stack.push(site.operand);
+            }
+            // RS
+            11 => {
+                if let Some(frame) = self.frames.pop() {
+                    self.stack.pop();
+                    if !self.stack.contains(&frame.address) {
+                        let subroutine = self.subroutines.entry(frame.address).or_default();
+                        subroutine.inclusive += self.total - frame.entered;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Execution counts by label if `symbols` has any, otherwise by address,
+    /// then by opcode and by subroutine.
+    pub fn table(&self, symbols: &SymbolFile) -> String {
+        let percent = |count: u64| 100.0 * count as f64 / self.total.max(1) as f64;
+        let mut out = format!("{} instructions executed\n", self.total);
+
+        let mut places: BTreeMap<String, u64> = BTreeMap::new();
+        for (address, count) in self.addresses.iter() {
+            let place = match symbols.labels.is_empty() {
+                true => format!("{:03X}", address),
+                false => group(symbols, *address),
+            };
+            *places.entry(place).or_default() += count;
+        }
+        let heading = match symbols.labels.is_empty() {
+            true => "ADDRESS",
+            false => "LABEL",
+        };
+        out.push_str(&format!("\n{:<20} {:>12} {:>7}\n", heading, "COUNT", "%"));
+        for (place, count) in by_count(places) {
+            out.push_str(&format!(
+                "{:<20} {:>12} {:>6.2}%\n",
+                place,
+                count,
+                percent(count)
+            ));
+        }
+
+        let mnemonics = Mnemonics::new().from_code;
+        let opcodes = (0..16u8)
+            .filter(|opcode| self.opcodes[*opcode as usize] > 0)
+            .map(|opcode| {
+                let name = mnemonics[&opcode].to_uppercase();
+                (name, self.opcodes[opcode as usize])
+            });
+        out.push_str(&format!("\n{:<20} {:>12} {:>7}\n", "OPCODE", "COUNT", "%"));
+        for (name, count) in by_count(opcodes) {
+            out.push_str(&format!(
+                "{:<20} {:>12} {:>6.2}%\n",
+                name,
+                count,
+                percent(count)
+            ));
+        }
+
+        if !self.subroutines.is_empty() {
+            let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+            subroutines.sort_by(|(a, x), (b, y)| y.inclusive.cmp(&x.inclusive).then(a.cmp(b)));
+            out.push_str(&format!(
+                "\n{:<20} {:>8} {:>12} {:>12}\n",
+                "SUBROUTINE", "CALLS", "INCLUSIVE", "EXCLUSIVE"
+            ));
+            for (address, subroutine) in subroutines {
+                out.push_str(&format!(
+                    "{:<20} {:>8} {:>12} {:>12}\n",
+                    name(symbols, *address),
+                    subroutine.calls,
+                    subroutine.inclusive,
+                    subroutine.exclusive
+                ));
+            }
+        }
+        out
+    }
+
+    /// One `main;OUTER;INNER count` line per chain of subroutines, sorted.
+    pub fn folded(&self, symbols: &SymbolFile) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let mut frames = vec!["main".to_string()];
+                frames.extend(stack.iter().map(|address| name(symbols, *address)));
+                format!("{} {}\n", frames.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+/// The label at `address`, or the address itself.
+fn name(symbols: &SymbolFile, address: u16) -> String {
+    symbols
+        .label_for(address)
+        .unwrap_or_else(|| format!("{:03X}", address))
+}
+
+/// The closest label at or before `address`, or the address itself.
+fn group(symbols: &SymbolFile, address: u16) -> String {
+    let label = name(symbols, address);
+    match label.split_once('+') {
+        Some((label, _)) => label.to_string(),
+        None => label,
+    }
+}
+
+/// Sorts by count, highest first, then by name.
+fn by_count<I: IntoIterator<Item = (String, u64)>>(counts: I) -> Vec<(String, u64)> {
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+    counts
+}
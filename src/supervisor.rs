@@ -0,0 +1,134 @@
+use crate::{MachineFault, StepOutcome, CPU};
+use std::collections::HashMap;
+
+/// A service run by `OS`, selected by its operand. It is given the CPU with
+/// the PC past the `OS` instruction.
+pub type OsService = Box<dyn FnMut(&mut CPU) -> Result<StepOutcome, MachineFault>>;
+
+/// Stops the machine with AC, which must not be negative, as the exit status.
+pub(crate) const EXIT: u16 = 0;
+/// Writes AC to device 0 in decimal.
+pub(crate) const PRINT_NUMBER: u16 = 1;
+/// Reads a decimal number between -128 and 127 from device 0 into AC.
+pub(crate) const READ_NUMBER: u16 = 2;
+/// Writes the zero-terminated string at the address in the next word to
+/// device 0.
+pub(crate) const PRINT_STRING: u16 = 3;
+/// Stores the number of instructions executed so far, as 4 bytes most
+/// significant first, at the address in the next word.
+pub(crate) const CYCLE_COUNT: u16 = 4;
+/// Stops a debugger as if a breakpoint had been hit. Does nothing otherwise.
+pub(crate) const BREAK: u16 = 5;
+
+/// The services every CPU starts with.
+pub(crate) fn builtin_services() -> HashMap<u16, OsService> {
+    let mut services: HashMap<u16, OsService> = HashMap::new();
+    services.insert(EXIT, Box::new(exit));
+    services.insert(PRINT_NUMBER, Box::new(print_number));
+    services.insert(READ_NUMBER, Box::new(read_number));
+    services.insert(PRINT_STRING, Box::new(print_string));
+    services.insert(CYCLE_COUNT, Box::new(cycle_count));
+    services.insert(BREAK, Box::new(|_: &mut CPU| Ok(StepOutcome::Break)));
+    services
+}
+
+fn exit(cpu: &mut CPU) -> Result<StepOutcome, MachineFault> {
+    let status = cpu.ac();
+    if status < 0 {
+        return Err(MachineFault::BadExitStatus(cpu.last_site(), status));
+    }
+    debug!("Exiting with status {}", status);
+    Ok(StepOutcome::Exited(status as u8))
+}
+
+fn print_number(cpu: &mut CPU) -> Result<StepOutcome, MachineFault> {
+    let text = cpu.ac().to_string();
+    write(cpu, text.as_bytes())?;
+    Ok(StepOutcome::Continued)
+}
+
+/// Skips leading whitespace, then reads an optional `-` and digits up to the
+/// first byte that is not one, which is consumed.
+fn read_number(cpu: &mut CPU) -> Result<StepOutcome, MachineFault> {
+    let site = cpu.last_site();
+    let mut text = String::new();
+    loop {
+        let byte = cpu
+            .device_mut(0)
+            .ok_or(MachineFault::UnmappedDevice(site, 0))?
+            .read()
+            .map_err(|err| MachineFault::InputError(site, err.to_string()))?;
+        match byte {
+            Some(byte) if text.is_empty() && byte.is_ascii_whitespace() => (),
+            Some(b'-') if text.is_empty() => text.push('-'),
+            Some(byte) if byte.is_ascii_digit() => text.push(byte as char),
+            None if text.is_empty() => {
+                cpu.set_pc(site.pc);
+                debug!("No input available, PC set back to {:03X}", site.pc);
+                return Ok(StepOutcome::WaitingOnInput);
+            }
+            _ => break,
+        }
+    }
+    let value = text.parse::<i8>().map_err(|_| {
+        let err = match text.as_str() {
+            "" | "-" => "expected a decimal number".to_string(),
+            _ => format!("{} does not fit in AC", text),
+        };
+        MachineFault::InputError(site, err)
+    })?;
+    cpu.set_ac(value);
+    debug!("AC set to {:02X} ({} in decimal)", value, value);
+    Ok(StepOutcome::Continued)
+}
+
+fn print_string(cpu: &mut CPU) -> Result<StepOutcome, MachineFault> {
+    let site = cpu.last_site();
+    let address = argument(cpu)?;
+    let text = cpu.memory()[address as usize..]
+        .split(|byte| *byte == 0)
+        .next()
+        .unwrap_or_default()
+        .to_vec();
+    if address as usize + text.len() >= cpu.memory().len() {
+        return Err(MachineFault::AddressOutOfRange(
+            site,
+            cpu.memory().len() as u16,
+        ));
+    }
+    write(cpu, &text)?;
+    Ok(StepOutcome::Continued)
+}
+
+fn cycle_count(cpu: &mut CPU) -> Result<StepOutcome, MachineFault> {
+    let site = cpu.last_site();
+    let address = argument(cpu)? as usize;
+    let count = (cpu.cycles() as u32).to_be_bytes();
+    let cells = cpu
+        .memory_mut()
+        .get_mut(address..address + count.len())
+        .ok_or(MachineFault::AddressOutOfRange(site, address as u16))?;
+    cells.copy_from_slice(&count);
+    Ok(StepOutcome::Continued)
+}
+
+/// Takes the address in the word after the `OS` instruction, moving the PC
+/// past it.
+fn argument(cpu: &mut CPU) -> Result<u16, MachineFault> {
+    let site = cpu.last_site();
+    let pc = cpu.pc();
+    let word = cpu
+        .memory()
+        .get(pc as usize..pc as usize + 2)
+        .ok_or(MachineFault::AddressOutOfRange(site, pc))?;
+    let address = u16::from_be_bytes([word[0], word[1]]) & 0x0FFF;
+    cpu.set_pc(pc + 2);
+    Ok(address)
+}
+
+fn write(cpu: &mut CPU, bytes: &[u8]) -> Result<(), MachineFault> {
+    for byte in bytes {
+        cpu.put_byte(0, *byte)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::number::parse_address;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// Position of a statement in an assembly source file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Labels and source positions of an assembled program, saved next to its tape.
+///
+/// The file is plain text with one record per line:
+///
+/// ```text
+/// ; comments start with a semicolon
+/// SYM  LOOP /F04
+/// LINE /F04 inputs/hello_world.asm:12
+/// ```
+#[derive(Debug, Default)]
+pub struct SymbolFile {
+    pub labels: BTreeMap<String, u16>,
+    pub lines: BTreeMap<u16, SourceLine>,
+}
+
+impl SymbolFile {
+    pub fn new() -> SymbolFile {
+        SymbolFile::default()
+    }
+
+    pub fn load(path: &str) -> Result<SymbolFile, String> {
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut symbols = SymbolFile::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let malformed = || format!("Malformed line {}: {}", idx + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                [comment, ..] if comment.starts_with(';') => (),
+                ["SYM", name, address] => {
+                    let address = parse_address(address).ok_or_else(malformed)?;
+                    symbols.labels.insert(name.to_string(), address);
+                }
+                ["LINE", address, _, ..] => {
+                    let address = parse_address(address).ok_or_else(malformed)?;
+                    let position = line.trim().splitn(3, char::is_whitespace).nth(2);
+                    let position = position.map(|p| p.trim()).ok_or_else(malformed)?;
+                    let idx = position.rfind(':').ok_or_else(malformed)?;
+                    let number = position[idx + 1..].parse().map_err(|_| malformed())?;
+                    symbols.lines.insert(
+                        address,
+                        SourceLine {
+                            file: position[..idx].to_string(),
+                            line: number,
+                        },
+                    );
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut contents = String::from("; sisprog symbols\n");
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, address)| (**address, name.as_str()));
+        for (name, address) in labels {
+            contents.push_str(&format!("SYM  {} /{:03X}\n", name, address));
+        }
+        for (address, position) in self.lines.iter() {
+            contents.push_str(&format!("LINE /{:03X} {}\n", address, position));
+        }
+        fs::write(path, contents)
+    }
+
+    /// Formats `address` relative to the closest label at or before it, e.g. `LOOP+2`.
+    pub fn label_for(&self, address: u16) -> Option<String> {
+        self.labels
+            .iter()
+            .filter(|(_, &a)| a <= address)
+            .max_by_key(|(name, &a)| (a, std::cmp::Reverse(name.as_str())))
+            .map(|(name, &a)| {
+                if a == address {
+                    name.clone()
+                } else {
+                    format!("{}+{}", name, address - a)
+                }
+            })
+    }
+}
@@ -0,0 +1,520 @@
+use std::fmt;
+
+/// A run of bytes to be placed in memory starting at `origin`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub origin: u16,
+    pub data: Vec<u8>,
+}
+
+/// Most bytes a single record on the tape can hold, and most records a tape
+/// can hold.
+const MAX_RECORDS: usize = 255;
+
+/// Starts a tape with extensions that only the CPU and `inspect` read. No
+/// plain tape starts this way, since the third byte would be the start of an
+/// instruction other than `JP`.
+pub(crate) const EXTENDED_MAGIC: &[u8; 3] = b"SIS";
+/// Flag of an extended tape whose records carry relocation bits.
+pub(crate) const RELOCATABLE: u8 = 0x01;
+/// Flag of an extended tape whose records end with a checksum.
+pub(crate) const CHECKSUMS: u8 = 0x02;
+
+/// A program as written by the assembler and read by the loader.
+///
+/// The layout is one byte with the number of blocks, the two byte `JP`
+/// instruction to the entry point, then for each block a two byte origin,
+/// a length byte and the payload. Blocks longer than 255 bytes are written
+/// as several records, one after the other.
+///
+/// As in the loader, a count or length byte of 0 on a plain tape stands for
+/// 256.
+///
+/// An extended tape starts with `SIS` and a byte of flags. If relocatable,
+/// each payload is followed by its relocation bits, one per byte of payload,
+/// most significant bit first. A set bit marks an instruction whose operand
+/// is an offset from the load address. With checksums, each record ends
+/// with a byte that brings the sum of the record to a multiple of 256. Only
+/// the CPU can load these, not the loader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tape {
+    /// The instruction the loader jumps through once every block is in place.
+    pub entry: u16,
+    pub blocks: Vec<Block>,
+    /// Address of every instruction to relocate, if the tape is relocatable.
+    pub relocations: Option<Vec<u16>>,
+    /// Whether each record is written with a checksum.
+    pub checksums: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TapeError {
+    /// The tape ended before the byte at this offset could be read.
+    UnexpectedEof(usize),
+    /// A relocation marks an address outside of every instruction.
+    BadRelocation(u16),
+    /// The operand of the instruction at this address no longer fits in 12
+    /// bits once relocated.
+    OperandOutOfRange(u16),
+    /// A block would end past the last address in memory.
+    OutOfMemory(usize),
+    /// A load address was given for a tape that cannot be moved.
+    NotRelocatable,
+    /// The tape needs this many records, more than its count byte can hold.
+    TooManyRecords(usize),
+    /// The tape has no records, which its count byte cannot say.
+    NoRecords,
+    /// The record at this index does not add up to its checksum.
+    BadChecksum(usize),
+}
+
+impl fmt::Display for TapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TapeError::UnexpectedEof(offset) => {
+                write!(f, "Tape ends prematurely at byte {}", offset)
+            }
+            TapeError::BadRelocation(address) => {
+                write!(
+                    f,
+                    "Tape relocates {:03X}, which is not an instruction",
+                    address
+                )
+            }
+            TapeError::OperandOutOfRange(address) => write!(
+                f,
+                "Operand of the instruction at {:03X} no longer fits in 12 bits",
+                address
+            ),
+            TapeError::OutOfMemory(end) => {
+                write!(f, "Tape ends at {:03X}, past the end of memory", end)
+            }
+            TapeError::NotRelocatable => write!(f, "Tape is not relocatable"),
+            TapeError::TooManyRecords(count) => write!(
+                f,
+                "Tape needs {} records, but at most {} fit on a tape",
+                count, MAX_RECORDS
+            ),
+            TapeError::NoRecords => write!(
+                f,
+                "Tape has no records, but the loader reads a count of 0 as 256"
+            ),
+            TapeError::BadChecksum(record) => {
+                write!(f, "Record {} does not match its checksum", record + 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TapeError {}
+
+impl Tape {
+    /// Reads the tape at the start of `bytes`. Also returns how many bytes
+    /// it takes, since whatever follows is input for the program.
+    pub fn parse(bytes: &[u8]) -> Result<(Tape, usize), TapeError> {
+        let next = |offset: &mut usize| {
+            let byte = bytes.get(*offset).copied();
+            *offset += 1;
+            byte.ok_or(TapeError::UnexpectedEof(*offset - 1))
+        };
+        let mut offset = 0;
+        let mut flags = 0;
+        let extended = bytes.starts_with(EXTENDED_MAGIC);
+        if extended {
+            offset = EXTENDED_MAGIC.len();
+            flags = next(&mut offset)?;
+        }
+        let mut relocations = (flags & RELOCATABLE != 0).then(Vec::new);
+        // The loader only checks for the last record after loading one
+        let count = match next(&mut offset)? {
+            0 if !extended => 256,
+            count => count as usize,
+        };
+        let entry = u16::from_be_bytes([next(&mut offset)?, next(&mut offset)?]);
+        let mut blocks = vec![];
+        for record in 0..count {
+            let start = offset;
+            let origin = u16::from_be_bytes([next(&mut offset)?, next(&mut offset)?]);
+            let len = match next(&mut offset)? {
+                // The loader only checks for the end after placing a byte
+                0 if !extended => 256,
+                len => len as u16,
+            };
+            let data = (0..len)
+                .map(|_| next(&mut offset))
+                .collect::<Result<Vec<u8>, _>>()?;
+            if let Some(relocations) = relocations.as_mut() {
+                for chunk in 0..len.div_ceil(8) {
+                    let bits = next(&mut offset)?;
+                    let marked = (0..8).filter(|bit| bits & (0x80 >> bit) != 0);
+                    relocations.extend(marked.map(|bit| origin.wrapping_add(chunk * 8 + bit)));
+                }
+            }
+            if flags & CHECKSUMS != 0 {
+                next(&mut offset)?;
+                if checksum(&bytes[start..offset]) != 0 {
+                    return Err(TapeError::BadChecksum(record));
+                }
+            }
+            blocks.push(Block { origin, data });
+        }
+        let tape = Tape {
+            entry,
+            blocks,
+            relocations,
+            checksums: flags & CHECKSUMS != 0,
+        };
+        Ok((tape, offset))
+    }
+
+    /// Lays the tape out as the loader reads it, or as the CPU reads it if
+    /// the tape is extended.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TapeError> {
+        let records = self.records();
+        if records.len() > MAX_RECORDS {
+            return Err(TapeError::TooManyRecords(records.len()));
+        }
+        if records.is_empty() {
+            return Err(TapeError::NoRecords);
+        }
+        let mut bytes = vec![];
+        let mut flags = 0;
+        if self.relocations.is_some() {
+            flags |= RELOCATABLE;
+        }
+        if self.checksums {
+            flags |= CHECKSUMS;
+        }
+        if flags != 0 {
+            bytes.extend(EXTENDED_MAGIC);
+            bytes.push(flags);
+        }
+        bytes.push(records.len() as u8);
+        bytes.extend(self.entry.to_be_bytes());
+        for block in records.iter() {
+            let start = bytes.len();
+            bytes.extend(block.origin.to_be_bytes());
+            bytes.push(block.data.len() as u8);
+            bytes.extend(&block.data);
+            if let Some(relocations) = self.relocations.as_ref() {
+                let mut bits = vec![0; block.data.len().div_ceil(8)];
+                for address in relocations.iter() {
+                    let idx = address.wrapping_sub(block.origin) as usize;
+                    if idx < block.data.len() {
+                        bits[idx / 8] |= 0x80 >> (idx % 8);
+                    }
+                }
+                bytes.extend(bits);
+            }
+            if self.checksums {
+                bytes.push(checksum(&bytes[start..]));
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Splits the blocks into pieces short enough for the length byte.
+    /// Empty blocks are left out, the loader can't skip them.
+    fn records(&self) -> Vec<Block> {
+        let mut records = vec![];
+        for block in self.blocks.iter() {
+            for (idx, data) in block.data.chunks(MAX_RECORDS).enumerate() {
+                records.push(Block {
+                    origin: block.origin + (idx * MAX_RECORDS) as u16,
+                    data: data.to_vec(),
+                });
+            }
+        }
+        records
+    }
+
+    /// Moves a relocatable tape up by `base`, giving an absolute tape. Any
+    /// tape can be "moved" by 0.
+    pub fn relocate(&self, base: u16) -> Result<Tape, TapeError> {
+        let relocations = match (self.relocations.as_ref(), base) {
+            (Some(relocations), _) => relocations.as_slice(),
+            (None, 0) => &[],
+            (None, _) => return Err(TapeError::NotRelocatable),
+        };
+        let mut blocks = self.blocks.clone();
+        for address in relocations.iter() {
+            relocate(&mut blocks, *address, base)?;
+        }
+        for block in blocks.iter_mut() {
+            let origin = block.origin as usize + base as usize;
+            let end = origin + block.data.len();
+            if end > 0x1000 {
+                return Err(TapeError::OutOfMemory(end));
+            }
+            block.origin = origin as u16;
+        }
+        let entry = self.entry_point() as usize + base as usize;
+        if entry > 0x0FFF {
+            return Err(TapeError::OutOfMemory(entry));
+        }
+        let entry = (self.entry & 0xF000) | entry as u16;
+        Ok(Tape {
+            entry,
+            blocks,
+            relocations: None,
+            checksums: self.checksums,
+        })
+    }
+
+    /// Address the loader jumps to after loading.
+    pub fn entry_point(&self) -> u16 {
+        self.entry & 0x0FFF
+    }
+}
+
+#[cfg(test)]
+impl Tape {
+    /// An absolute tape without checksums.
+    pub(crate) fn plain(entry: u16, blocks: Vec<Block>) -> Tape {
+        Tape {
+            entry,
+            blocks,
+            relocations: None,
+            checksums: false,
+        }
+    }
+}
+
+/// The tape that loads every byte of `memory` that is `Some`, with a block
+/// for each run of them, then jumps to `entry`.
+pub(crate) fn from_memory(memory: &[Option<u8>], entry: u16) -> Tape {
+    let mut blocks: Vec<Block> = vec![];
+    for (address, byte) in memory.iter().enumerate() {
+        let byte = match byte {
+            Some(byte) => *byte,
+            None => continue,
+        };
+        match blocks.last_mut() {
+            Some(block) if block.origin as usize + block.data.len() == address => {
+                block.data.push(byte)
+            }
+            _ => blocks.push(Block {
+                origin: address as u16,
+                data: vec![byte],
+            }),
+        }
+    }
+    Tape {
+        entry,
+        blocks,
+        relocations: None,
+        checksums: false,
+    }
+}
+
+/// The byte that brings the sum of `bytes` to a multiple of 256.
+pub(crate) fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// Adds `offset` to the 12-bit operand of the instruction at `address`,
+/// whose bytes may be at the end of one block and the start of the next.
+pub(crate) fn relocate(blocks: &mut [Block], address: u16, offset: u16) -> Result<(), TapeError> {
+    let bad = TapeError::BadRelocation(address);
+    let (msb_block, msb_idx) = locate(blocks, address).ok_or(bad.clone())?;
+    let (lsb_block, lsb_idx) = locate(blocks, address.wrapping_add(1)).ok_or(bad)?;
+    let msb = blocks[msb_block].data[msb_idx];
+    let lsb = blocks[lsb_block].data[lsb_idx];
+    let word = u16::from_be_bytes([msb, lsb]);
+    let operand = (word & 0x0FFF) + offset;
+    if operand > 0x0FFF {
+        return Err(TapeError::OperandOutOfRange(address));
+    }
+    let [msb, lsb] = ((word & 0xF000) | operand).to_be_bytes();
+    blocks[msb_block].data[msb_idx] = msb;
+    blocks[lsb_block].data[lsb_idx] = lsb;
+    Ok(())
+}
+
+/// Index of the block holding `address`, and of the byte within it.
+fn locate(blocks: &[Block], address: u16) -> Option<(usize, usize)> {
+    blocks.iter().enumerate().find_map(|(idx, block)| {
+        let offset = address.checked_sub(block.origin)? as usize;
+        (offset < block.data.len()).then_some((idx, offset))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_counts_the_bytes_of_the_tape() {
+        let bytes = [1, 0x01, 0x00, 0x01, 0x00, 2, 0xC1, 0x00, b'i', b'n'];
+        let (tape, len) = Tape::parse(&bytes).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(tape.blocks[0].data, vec![0xC1, 0x00]);
+    }
+
+    #[test]
+    fn length_zero_is_256_like_the_loader() {
+        let mut bytes = vec![1, 0x01, 0x00, 0x01, 0x00, 0];
+        bytes.extend([0xC1; 256]);
+        let (tape, len) = Tape::parse(&bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(tape.blocks[0].data.len(), 256);
+    }
+
+    #[test]
+    fn plain_tape_round_trips() {
+        let tape = Tape::plain(
+            0x0100,
+            vec![
+                Block {
+                    origin: 0x100,
+                    data: vec![0x01, 0x20, 0xC0, 0x00],
+                },
+                Block {
+                    origin: 0x200,
+                    data: b"Hello".to_vec(),
+                },
+            ],
+        );
+        let bytes = tape.to_bytes().unwrap();
+        assert_eq!(&bytes[..6], &[2, 0x01, 0x00, 0x01, 0x00, 4]);
+        assert_eq!(Tape::parse(&bytes).unwrap(), (tape, bytes.len()));
+    }
+
+    #[test]
+    fn long_blocks_are_split_into_records() {
+        let data: Vec<u8> = (0..300).map(|byte| byte as u8).collect();
+        let tape = Tape::plain(
+            0x0100,
+            vec![Block {
+                origin: 0x100,
+                data: data.clone(),
+            }],
+        );
+        let bytes = tape.to_bytes().unwrap();
+        assert_eq!(bytes[0], 2);
+        let (parsed, len) = Tape::parse(&bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(
+            parsed.blocks,
+            vec![
+                Block {
+                    origin: 0x100,
+                    data: data[..255].to_vec(),
+                },
+                Block {
+                    origin: 0x1FF,
+                    data: data[255..].to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extended_tape_round_trips() {
+        let mut tape = Tape::plain(
+            0x0100,
+            vec![
+                Block {
+                    origin: 0x000,
+                    data: vec![0x00, 0x0A, 0x80, 0x08, 0x70, 0x04, 0xC0, 0x00, 0x12],
+                },
+                Block {
+                    origin: 0x020,
+                    data: vec![0x10, 0x00],
+                },
+            ],
+        );
+        tape.entry = 0x0000;
+        tape.relocations = Some(vec![0x000, 0x002, 0x008, 0x020]);
+        tape.checksums = true;
+        let bytes = tape.to_bytes().unwrap();
+        assert_eq!(&bytes[..4], b"SIS\x03");
+        assert_eq!(Tape::parse(&bytes).unwrap(), (tape, bytes.len()));
+    }
+
+    #[test]
+    fn corrupted_record_fails_its_checksum() {
+        let mut tape = Tape::plain(
+            0x0100,
+            vec![
+                Block {
+                    origin: 0x100,
+                    data: vec![0x01, 0x20],
+                },
+                Block {
+                    origin: 0x200,
+                    data: vec![0x00],
+                },
+            ],
+        );
+        tape.checksums = true;
+        let mut bytes = tape.to_bytes().unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 0xFF;
+        assert_eq!(Tape::parse(&bytes), Err(TapeError::BadChecksum(1)));
+    }
+
+    #[test]
+    fn truncated_tape_is_an_error() {
+        let bytes = Tape::plain(
+            0x0100,
+            vec![Block {
+                origin: 0x100,
+                data: vec![0x01, 0x20],
+            }],
+        )
+        .to_bytes()
+        .unwrap();
+        assert_eq!(
+            Tape::parse(&bytes[..bytes.len() - 1]),
+            Err(TapeError::UnexpectedEof(bytes.len() - 1))
+        );
+    }
+
+    #[test]
+    fn too_many_records_are_refused() {
+        let blocks = (0..256)
+            .map(|idx| Block {
+                origin: idx * 2,
+                data: vec![0],
+            })
+            .collect();
+        assert_eq!(
+            Tape::plain(0x0100, blocks).to_bytes(),
+            Err(TapeError::TooManyRecords(256))
+        );
+    }
+
+    #[test]
+    fn count_zero_is_256_like_the_loader() {
+        let mut bytes = vec![0, 0x01, 0x00];
+        for idx in 0..256u16 {
+            bytes.extend((0x100 + idx).to_be_bytes());
+            bytes.extend([1, idx as u8]);
+        }
+        let (tape, len) = Tape::parse(&bytes).unwrap();
+        assert_eq!(len, bytes.len());
+        assert_eq!(tape.blocks.len(), 256);
+    }
+
+    #[test]
+    fn empty_program_is_refused() {
+        let tape = Tape::plain(0x0100, vec![]);
+        assert_eq!(tape.to_bytes(), Err(TapeError::NoRecords));
+    }
+
+    #[test]
+    fn relocating_a_high_origin_is_out_of_memory() {
+        let tape = Tape::plain(
+            0x0000,
+            vec![Block {
+                origin: 0xFFFF,
+                data: vec![0],
+            }],
+        );
+        assert_eq!(tape.relocate(0), Err(TapeError::OutOfMemory(0x10000)));
+    }
+}
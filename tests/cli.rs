@@ -0,0 +1,204 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A scratch directory for one test, emptied on creation.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sisprog-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn sisprog(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sisprog"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Assembles the loader and hello world into `dir`.
+fn assemble_hello_world(dir: &Path) -> (String, String) {
+    let path = |file: &str| dir.join(file).to_str().unwrap().to_string();
+    let assets = concat!(env!("CARGO_MANIFEST_DIR"), "/src/assets");
+    let (loader, program) = (path("loader.bin"), path("hello_world.bin"));
+    let status = sisprog(&["assembler", &loader, &format!("{}/loader.asm", assets)]);
+    assert!(status.status.success(), "{:?}", status);
+    let status = sisprog(&[
+        "assembler",
+        &program,
+        &format!("{}/hello_world.asm", assets),
+    ]);
+    assert!(status.status.success(), "{:?}", status);
+    (loader, program)
+}
+
+#[test]
+fn cpu_runs_without_profile() {
+    let dir = scratch("no-profile");
+    let (loader, program) = assemble_hello_world(&dir);
+    let output = dir.join("output.bin");
+    let output = output.to_str().unwrap();
+
+    let run = sisprog(&["cpu", output, &program, "-L", &loader]);
+    assert!(run.status.success(), "{:?}", run);
+    assert_eq!(fs::read(output).unwrap(), b"Hello, world");
+
+    fs::remove_file(output).unwrap();
+    let run = sisprog(&["cpu", output, &program, "--format", "tape"]);
+    assert!(run.status.success(), "{:?}", run);
+    assert_eq!(fs::read(output).unwrap(), b"Hello, world");
+}
+
+#[test]
+fn cpu_writes_table_profile_by_default() {
+    let dir = scratch("profile");
+    let (loader, program) = assemble_hello_world(&dir);
+    let output = dir.join("output.bin");
+    let profile = dir.join("profile.txt");
+
+    let run = sisprog(&[
+        "cpu",
+        output.to_str().unwrap(),
+        &program,
+        "-L",
+        &loader,
+        "--profile",
+        profile.to_str().unwrap(),
+    ]);
+    assert!(run.status.success(), "{:?}", run);
+    let profile = fs::read_to_string(profile).unwrap();
+    assert!(profile.contains("instructions executed"), "{}", profile);
+    assert!(profile.contains("OPCODE"), "{}", profile);
+}
+
+#[test]
+fn file_devices_keep_their_files() {
+    let dir = scratch("devices");
+    let (_, program) = assemble_hello_world(&dir);
+    let output = dir.join("output.bin");
+    let data = dir.join("data.in");
+    fs::write(&output, b"kept").unwrap();
+    fs::write(&data, b"data").unwrap();
+    let stdout = dir.join("stdout.bin");
+
+    let run = sisprog(&[
+        "cpu",
+        output.to_str().unwrap(),
+        &program,
+        "--format",
+        "tape",
+        "--device",
+        &format!("0=file:{}", stdout.to_str().unwrap()),
+        "--device",
+        &format!("1=file:{}", data.to_str().unwrap()),
+    ]);
+    assert!(run.status.success(), "{:?}", run);
+    assert_eq!(fs::read(&stdout).unwrap(), b"Hello, world");
+    assert_eq!(fs::read(&data).unwrap(), b"data");
+    assert_eq!(fs::read(&output).unwrap(), b"kept");
+}
+
+#[test]
+fn program_exit_status_is_apart_from_faults() {
+    let dir = scratch("exit");
+    let source = dir.join("exit.asm");
+    let program = dir.join("exit.bin");
+    let output = dir.join("output.bin");
+    let run = |status: &str| {
+        fs::write(
+            &source,
+            format!("    @ /100\nSTART LV {}\n    OS 0\n    # START\n", status),
+        )
+        .unwrap();
+        let assembled = sisprog(&[
+            "assembler",
+            program.to_str().unwrap(),
+            source.to_str().unwrap(),
+        ]);
+        assert!(assembled.status.success(), "{:?}", assembled);
+        let run = sisprog(&[
+            "cpu",
+            output.to_str().unwrap(),
+            program.to_str().unwrap(),
+            "--format",
+            "tape",
+        ]);
+        run.status.code()
+    };
+    assert_eq!(run("0"), Some(0));
+    assert_eq!(run("10"), Some(74));
+    assert_eq!(run("127"), Some(191));
+}
+
+#[test]
+fn debugger_rejects_addresses_past_u16() {
+    let dir = scratch("debug");
+    let (loader, program) = assemble_hello_world(&dir);
+    let output = dir.join("output.bin");
+    let mut debug = Command::new(env!("CARGO_BIN_EXE_sisprog"))
+        .args(["debug", output.to_str().unwrap(), &program, "-L", &loader])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    debug
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"x /FFFF+1\nb 65000+1000\nq\n")
+        .unwrap();
+    let run = debug.wait_with_output().unwrap();
+    assert!(run.status.success(), "{:?}", run);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    assert!(
+        stdout.contains("Address 10000 is out of range"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Address 101D0 is out of range"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn loader_refuses_extended_tapes() {
+    let dir = scratch("extended");
+    let (loader, _) = assemble_hello_world(&dir);
+    let program = dir.join("checked.bin");
+    let output = dir.join("output.bin");
+    let assembled = sisprog(&[
+        "assembler",
+        "--checksum",
+        program.to_str().unwrap(),
+        concat!(env!("CARGO_MANIFEST_DIR"), "/src/assets/hello_world.asm"),
+    ]);
+    assert!(assembled.status.success(), "{:?}", assembled);
+
+    let run = sisprog(&[
+        "cpu",
+        output.to_str().unwrap(),
+        program.to_str().unwrap(),
+        "-L",
+        &loader,
+    ]);
+    assert_eq!(run.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(stderr.contains("is an extended tape"), "{}", stderr);
+    assert!(stderr.contains("cpu --format tape"), "{}", stderr);
+
+    let missing = dir.join("missing.bin");
+    let run = sisprog(&[
+        "cpu",
+        output.to_str().unwrap(),
+        program.to_str().unwrap(),
+        "-L",
+        missing.to_str().unwrap(),
+    ]);
+    assert_eq!(run.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(stderr.starts_with("Unable to read "), "{}", stderr);
+}